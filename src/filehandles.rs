@@ -1,11 +1,21 @@
 /// Handles to opened inodes. Mainly indicates that they should not be garbage collected by the inode cache.
 
 use std::sync::Mutex;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::mem;
+use libc;
+use fuser::{FileAttr, FileType};
 use fasthashes::*;
+use helper::{fuse_dirent_type, fuse_mode_type, fd_attr};
 
 pub type FileHandle = u64;
 pub type Inode = u64;
+pub type LockOwner = u64;
 
 const HOTFILESIZE : usize = 100;
 
@@ -18,9 +28,90 @@ struct FileEntry {
     ino : Inode,
 }
 
+pub struct DirEntry {
+    pub ino : Inode,
+    pub kind : FileType,
+    pub name : OsString,
+    pub offset : u64, // telldir cookie of the next entry.
+}
+
+// A directory handle reads its entries lazily from its own stream: memory stays bounded whatever the size of the directory, and concurrent listings of the same directory do not interfere.
+pub struct DirStream {
+    dir : *mut libc::DIR,
+}
+// A DIR is only ever used by whoever took the stream out of HotFiles.
+unsafe impl Send for DirStream {}
+
+impl DirStream {
+    pub fn open(path: &Path) -> io::Result<DirStream> {
+        let path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let dir = unsafe { libc::opendir(path.as_ptr()) };
+        if dir.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DirStream{ dir : dir })
+    }
+    // FUSE offsets are the telldir cookies handed out with each entry, 0 being the start of the directory.
+    pub fn seek(&mut self, offset: u64) {
+        unsafe {
+            if offset == 0 {
+                libc::rewinddir(self.dir);
+            } else {
+                libc::seekdir(self.dir, offset as libc::c_long);
+            }
+        }
+    }
+    pub fn fd(&self) -> RawFd {
+        unsafe { libc::dirfd(self.dir) }
+    }
+    pub fn next_entry(&mut self) -> Option<io::Result<DirEntry>> {
+        let dirent = unsafe {
+            *libc::__errno_location() = 0;
+            libc::readdir(self.dir)
+        };
+        if dirent.is_null() {
+            // Either the end of the directory, or an error.
+            return match io::Error::last_os_error() {
+                ref e if e.raw_os_error() == Some(0) => None,
+                e => Some(Err(e)),
+            };
+        }
+        let (ino, d_type, name) = unsafe {
+            ((*dirent).d_ino, (*dirent).d_type, CStr::from_ptr((*dirent).d_name.as_ptr()))
+        };
+        let kind = match fuse_dirent_type(d_type) {
+            Some(kind) => kind,
+            None => {
+                // Some filesystems do not fill d_type in.
+                let mut st : libc::stat = unsafe { mem::zeroed() };
+                if unsafe { libc::fstatat(self.fd(), name.as_ptr(), &mut st as *mut libc::stat, libc::AT_SYMLINK_NOFOLLOW) } == -1 {
+                    return Some(Err(io::Error::last_os_error()));
+                }
+                fuse_mode_type(st.st_mode)
+            }
+        };
+        Some(Ok(DirEntry {
+            ino : ino as Inode,
+            kind : kind,
+            name : OsStr::from_bytes(name.to_bytes()).to_os_string(),
+            offset : unsafe { libc::telldir(self.dir) } as u64,
+        }))
+    }
+}
+impl Drop for DirStream {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.dir); }
+    }
+}
+
 struct HotFilesMutexed {
     by_fh : FastMap<FileHandle, FileEntry>,
     by_ino : FastMap<Inode, u64>, // a count for handled files because of the hard link case.
+    by_owner : FastMap<(Inode, LockOwner), File>, // one open file description per lock owner, holding its record locks.
+    dirs : FastMap<FileHandle, DirStream>,
     count : u64,
 }
 
@@ -32,6 +123,8 @@ impl HotFiles {
                 HotFilesMutexed {
                     by_fh : FastMap::with_capacity(HOTFILESIZE),
                     by_ino : FastMap::with_capacity(HOTFILESIZE),
+                    by_owner : FastMap::with_capacity(HOTFILESIZE),
+                    dirs : FastMap::with_capacity(HOTFILESIZE),
                     count : 0,
                 }
             )
@@ -46,6 +139,12 @@ impl HotFiles {
         hot.count = count;
         count
     }
+    pub fn make_dir_handle(&mut self, stream: DirStream, ino: Inode) -> FileHandle {
+        let fh = self.make_handle(None, ino);
+        let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
+        hot.dirs.insert(fh, stream);
+        fh
+    }
     pub fn take_file(&mut self, fh: FileHandle) -> File {
         let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
         hot.by_fh.remove(&fh).unwrap().file.unwrap()//directory functions should not take any handle.
@@ -54,8 +153,19 @@ impl HotFiles {
         let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
         hot.by_fh.insert(fh, FileEntry{file: Some(file), ino: ino});
     }
+    pub fn take_dir(&mut self, fh: FileHandle) -> Option<DirStream> {
+        let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
+        hot.dirs.remove(&fh)
+    }
+    pub fn restore_dir(&mut self, fh: FileHandle, stream: DirStream) {
+        let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
+        hot.dirs.insert(fh, stream);
+    }
     pub fn release_handle(&mut self, fh: FileHandle) {
         let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
+        if hot.dirs.remove(&fh).is_some() {
+            trace!("Freed directory stream of handle {}", fh);
+        }
         let ino = match hot.by_fh.remove(&fh) {
             Some(entry) => entry.ino,
             None => {
@@ -78,4 +188,54 @@ impl HotFiles {
         let hot = self.mutex.lock().expect("This is not supposed to happen...");
         hot.by_ino.contains_key(&ino)
     }
+    // Any open file of the inode, for when no path leads to it any longer. The descriptor is only valid until the handle is released.
+    pub fn fd(&self, ino: Inode) -> Option<RawFd> {
+        let hot = self.mutex.lock().expect("This is not supposed to happen...");
+        for entry in hot.by_fh.values() {
+            if entry.ino == ino {
+                if let Some(ref file) = entry.file {
+                    return Some(file.as_raw_fd());
+                }
+            }
+        }
+        None
+    }
+    // Attributes through any open file of the inode, for when no path leads to it any longer.
+    pub fn attr(&self, ino: Inode) -> io::Result<FileAttr> {
+        match self.fd(ino) {
+            Some(fd) => fd_attr(fd),
+            None => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+    // OFD locks belong to an open file description, not to a process: reopening the file once per lock owner makes them behave as the owner's POSIX record locks, which the source directory sees as well.
+    pub fn lock_fd(&mut self, fh: FileHandle, ino: Inode, owner: LockOwner) -> io::Result<RawFd> {
+        let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
+        if let Some(file) = hot.by_owner.get(&(ino, owner)) {
+            return Ok(file.as_raw_fd());
+        }
+        let fd = match hot.by_fh.get(&fh) {
+            Some(&FileEntry{file: Some(ref file), ..}) => file.as_raw_fd(),
+            _ => return Err(io::Error::from_raw_os_error(libc::EBADF)),
+        };
+        // Reopen with the same access mode, since read locks need read access and write locks write access.
+        let mode = unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_ACCMODE;
+        let file = match OpenOptions::new()
+                        .read(mode != libc::O_WRONLY)
+                        .write(mode != libc::O_RDONLY)
+                        .open(format!("/proc/self/fd/{}", fd)) {
+            Ok(file) => file,
+            Err(why) => return Err(why),
+        };
+        let owner_fd = file.as_raw_fd();
+        hot.by_owner.insert((ino, owner), file);
+        trace!("Opened a new file description for lock owner {} on inode {}", owner, ino);
+        Ok(owner_fd)
+    }
+    // Closing the owner's file description drops all its locks at once.
+    pub fn release_locks(&mut self, ino: Inode, owner: LockOwner) {
+        let mut hot = self.mutex.lock().expect("This is not supposed to happen...");
+        if hot.by_owner.remove(&(ino, owner)).is_some() {
+            trace!("Released record locks of lock owner {} on inode {}", owner, ino);
+        }
+    }
 }
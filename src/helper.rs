@@ -1,52 +1,442 @@
 // Just functions that may be useful to many modules.
 
-use std::fs::Metadata;
-use std::fs::FileType as StdFileType;
-use std::os::unix::fs::{MetadataExt, FileTypeExt, PermissionsExt};
-use time::Timespec;
-use fuse::{FileAttr, FileType};
-
-pub fn fuse_file_type(file_type : StdFileType) -> FileType {
-    if file_type.is_dir() == true {
-            FileType::Directory
-        } else if file_type.is_file() == true {
-            FileType::RegularFile
-        } else if file_type.is_symlink() == true {
-            FileType::Symlink
-        } else if file_type.is_block_device() == true {
-            FileType::BlockDevice
-        } else if file_type.is_fifo() == true {
-            FileType::NamedPipe
-        } else if file_type.is_char_device() == true {
-            FileType::CharDevice
+use std::{cmp, io, mem};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{MetadataExt, PermissionsExt, FileExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::ffi::{CStr, CString};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fuser::{FileAttr, FileType, TimeOrNow};
+use libc;
+
+const COPY_CHUNK : usize = 128 * 1024; // Buffer size of the userland fallback of copy_range.
+const COPY_FALLBACK_MAX : u64 = 4 * 1024 * 1024; // Bytes the userland fallback copies per request, so that it does not hold up the session for long.
+pub const OFFSET_MAX : u64 = 0x7fffffffffffffff; // End of a lock range spanning to the end of file, as the kernel sends it.
+
+// None when the filesystem left the type unknown.
+pub fn fuse_dirent_type(d_type : u8) -> Option<FileType> {
+    match d_type {
+        libc::DT_DIR => Some(FileType::Directory),
+        libc::DT_REG => Some(FileType::RegularFile),
+        libc::DT_LNK => Some(FileType::Symlink),
+        libc::DT_BLK => Some(FileType::BlockDevice),
+        libc::DT_FIFO => Some(FileType::NamedPipe),
+        libc::DT_CHR => Some(FileType::CharDevice),
+        libc::DT_SOCK => Some(FileType::Socket),
+        _ => None,
+    }
+}
+
+pub fn fuse_mode_type(mode : libc::mode_t) -> FileType {
+    match mode & libc::S_IFMT {
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFBLK => FileType::BlockDevice,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFSOCK => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
+pub fn mode_type(kind : FileType) -> libc::mode_t {
+    match kind {
+        FileType::Directory => libc::S_IFDIR,
+        FileType::Symlink => libc::S_IFLNK,
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::NamedPipe => libc::S_IFIFO,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::Socket => libc::S_IFSOCK,
+        FileType::RegularFile => libc::S_IFREG,
+    }
+}
+
+// Device number as the kernel expects it from FUSE (new_encode_dev).
+pub fn encode_dev(major : u32, minor : u32) -> u32 {
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+fn system_time(ts : &libc::statx_timestamp) -> SystemTime {
+    if ts.tv_sec >= 0 {
+        UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(-ts.tv_sec as u64) + Duration::from_nanos(ts.tv_nsec as u64)
+    }
+}
+
+fn epoch_timespec(time : SystemTime) -> libc::timespec {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => libc::timespec{ tv_sec : since.as_secs() as libc::time_t, tv_nsec : since.subsec_nanos() as libc::c_long },
+        Err(before) => {
+            let before = before.duration();
+            if before.subsec_nanos() == 0 {
+                libc::timespec{ tv_sec : -(before.as_secs() as libc::time_t), tv_nsec : 0 }
+            } else {
+                libc::timespec{ tv_sec : -(before.as_secs() as libc::time_t) - 1, tv_nsec : (1_000_000_000 - before.subsec_nanos()) as libc::c_long }
+            }
+        },
+    }
+}
+
+// Time to set as utimensat(2) takes it.
+pub fn utime_spec(time : TimeOrNow) -> libc::timespec {
+    match time {
+        TimeOrNow::SpecificTime(time) => epoch_timespec(time),
+        TimeOrNow::Now => libc::timespec{ tv_sec : 0, tv_nsec : libc::UTIME_NOW },
+    }
+}
+
+// Seconds since the epoch, for utime(2).
+pub fn utime_secs(time : TimeOrNow) -> libc::time_t {
+    match time {
+        TimeOrNow::SpecificTime(time) => epoch_timespec(time).tv_sec,
+        TimeOrNow::Now => epoch_timespec(SystemTime::now()).tv_sec,
+    }
+}
+
+pub fn fill_file_attr(stx : &libc::statx) -> FileAttr {
+    FileAttr{
+        ino : stx.stx_ino,
+        size : stx.stx_size,
+        blocks : stx.stx_blocks,
+        atime : system_time(&stx.stx_atime),
+        mtime : system_time(&stx.stx_mtime),
+        ctime : system_time(&stx.stx_ctime),
+        crtime : if stx.stx_mask & libc::STATX_BTIME != 0 {
+            system_time(&stx.stx_btime)
         } else {
-            // Sockets aren't supported apparently.
-            FileType::RegularFile
-        }
+            UNIX_EPOCH // Not every filesystem records birth time.
+        },
+        kind : fuse_mode_type(stx.stx_mode as libc::mode_t),
+        perm : stx.stx_mode & 0o7777,
+        nlink : stx.stx_nlink,
+        uid : stx.stx_uid,
+        gid : stx.stx_gid,
+        rdev : encode_dev(stx.stx_rdev_major, stx.stx_rdev_minor),
+        blksize : stx.stx_blksize,
+        flags : 0, // chflags(2) flags of macOS, never sent on Linux: lsattr gets the inode flags through ioctl.
+    }
 }
 
-pub fn fill_file_attr(md : &Metadata) -> FileAttr {
+// Attributes of an entry that could be listed but not looked at: only its inode and type are known.
+pub fn unknown_attr(ino : u64, kind : FileType) -> FileAttr {
     FileAttr{
-        ino : md.ino(),
-        size : md.size(),
-        blocks : md.blocks(),
-        atime : Timespec{ sec : md.atime(), nsec : md.atime_nsec() as i32, },
-        mtime : Timespec{ sec : md.mtime(), nsec : md.mtime_nsec() as i32, },
-        ctime : Timespec{ sec : md.ctime(), nsec : md.ctime_nsec() as i32, },
-        crtime : Timespec{ sec : 0, nsec : 0 }, //unavailable on Linux...
-        kind : fuse_file_type(md.file_type()),
-        perm : md.permissions().mode() as u16,
-        nlink : md.nlink() as u32,
-        uid : md.uid(),
-        gid : md.gid(),
-        rdev : md.rdev() as u32,
+        ino : ino,
+        size : 0,
+        blocks : 0,
+        atime : UNIX_EPOCH,
+        mtime : UNIX_EPOCH,
+        ctime : UNIX_EPOCH,
+        crtime : UNIX_EPOCH,
+        kind : kind,
+        perm : 0,
+        nlink : 1,
+        uid : 0,
+        gid : 0,
+        rdev : 0,
+        blksize : 0,
         flags : 0,
     }
 }
 
-use std::path::Path;
-use fuse::Request;
-use libc::EACCES;
+// Attributes of name relative to dirfd, without following symlinks (or of dirfd itself with AT_EMPTY_PATH).
+pub fn at_attr(dirfd : RawFd, name : &CStr, flags : libc::c_int) -> io::Result<FileAttr> {
+    let mut stx : libc::statx = unsafe { mem::zeroed() };
+    // Through syscall(2) rather than the libc wrapper, which older C libraries lack.
+    if unsafe {
+        libc::syscall(
+            libc::SYS_statx,
+            dirfd,
+            name.as_ptr(),
+            flags | libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            libc::STATX_BASIC_STATS | libc::STATX_BTIME,
+            &mut stx as *mut libc::statx
+        )
+    } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fill_file_attr(&stx))
+}
+
+pub fn path_attr(path : &Path) -> io::Result<FileAttr> {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => at_attr(libc::AT_FDCWD, &path, 0),
+        Err(_) => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    }
+}
+
+pub fn fd_attr(fd : RawFd) -> io::Result<FileAttr> {
+    at_attr(fd, CStr::from_bytes_with_nul(b"\0").unwrap(), libc::AT_EMPTY_PATH)
+}
+
+pub const OVERRIDE_STAT_XATTR : &'static str = "user.mirrorfs.override_stat";
+
+// Owners, mode (file type included) and device number an unprivileged mirror cannot set on the backing file, kept in OVERRIDE_STAT_XATTR as "uid:gid:mode:rdev", the mode being octal.
+// Only regular files and directories may carry user extended attributes.
+#[derive(Clone, Copy, Debug)]
+pub struct OverrideStat {
+    pub uid : u32,
+    pub gid : u32,
+    pub mode : u32,
+    pub rdev : u32,
+}
+
+impl OverrideStat {
+    pub fn of(attr : &FileAttr) -> OverrideStat {
+        OverrideStat {
+            uid : attr.uid,
+            gid : attr.gid,
+            mode : mode_type(attr.kind) as u32 | attr.perm as u32,
+            rdev : attr.rdev,
+        }
+    }
+
+    fn parse(value : &[u8]) -> Option<OverrideStat> {
+        let value = match ::std::str::from_utf8(value) {
+            Ok(value) => value,
+            Err(_) => return None,
+        };
+        let fields : Vec<&str> = value.split(':').collect();
+        if fields.len() != 4 {
+            return None;
+        }
+        match (fields[0].parse(), fields[1].parse(), u32::from_str_radix(fields[2], 8), fields[3].parse()) {
+            (Ok(uid), Ok(gid), Ok(mode), Ok(rdev)) => Some(OverrideStat {
+                uid : uid,
+                gid : gid,
+                mode : mode,
+                rdev : rdev,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, attr : &mut FileAttr) {
+        attr.uid = self.uid;
+        attr.gid = self.gid;
+        attr.kind = fuse_mode_type(self.mode as libc::mode_t);
+        attr.perm = (self.mode & 0o7777) as u16;
+        attr.rdev = self.rdev;
+    }
+}
+
+// The override of path, or of its open file fd. None if it has none, or if its filesystem has no extended attributes.
+pub fn get_override_stat(path : &Path, fd : Option<RawFd>) -> io::Result<Option<OverrideStat>> {
+    let name = CString::new(OVERRIDE_STAT_XATTR).unwrap();
+    let mut value = [0u8; 64];
+    let len = match fd {
+        Some(fd) => unsafe {
+            libc::fgetxattr(fd, name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len())
+        },
+        None => match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => unsafe {
+                libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len())
+            },
+            Err(_) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        },
+    };
+    if len == -1 {
+        let why = io::Error::last_os_error();
+        return match why.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(why),
+        };
+    }
+    match OverrideStat::parse(&value[..len as usize]) {
+        Some(stat) => Ok(Some(stat)),
+        None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    }
+}
+
+pub fn set_override_stat(path : &Path, fd : Option<RawFd>, stat : &OverrideStat) -> io::Result<()> {
+    let name = CString::new(OVERRIDE_STAT_XATTR).unwrap();
+    let value = format!("{}:{}:{:o}:{}", stat.uid, stat.gid, stat.mode, stat.rdev);
+    let res = match fd {
+        Some(fd) => unsafe {
+            libc::fsetxattr(fd, name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+        },
+        None => match CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => unsafe {
+                libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0)
+            },
+            Err(_) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        },
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Copy len bytes from one backing file to another, letting the kernel do it (and reflink on btrfs/XFS) when it can.
+// Falls back to copying through userland when the files are on different backing filesystems or the kernel cannot offload.
+// The fallback copies at most COPY_FALLBACK_MAX bytes: callers of copy_file_range(2) get a short count and ask for the rest.
+pub fn copy_range(src: &File, offset_in: u64, dst: &File, offset_out: u64, len: u64) -> io::Result<usize> {
+    let mut off_in = offset_in as libc::loff_t;
+    let mut off_out = offset_out as libc::loff_t;
+    let res = unsafe {
+        libc::copy_file_range(
+            src.as_raw_fd(),
+            &mut off_in as *mut libc::loff_t,
+            dst.as_raw_fd(),
+            &mut off_out as *mut libc::loff_t,
+            len as libc::size_t,
+            0
+        )
+    };
+    if res >= 0 {
+        trace!("Kernel copied {} bytes from offset {} to offset {}", res, offset_in, offset_out);
+        return Ok(res as usize);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EXDEV) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+            debug!("Kernel could not offload the copy ({:?}), copying through userland instead.", e);
+        },
+        _ => return Err(e),
+    }
+    let len = cmp::min(len, COPY_FALLBACK_MAX);
+    let mut buffer = vec![0u8; cmp::min(len, COPY_CHUNK as u64) as usize];
+    let mut copied : u64 = 0;
+    while copied < len {
+        let want = cmp::min(buffer.len() as u64, len - copied) as usize;
+        let n = match src.read_at(&mut buffer[..want], offset_in + copied) {
+            Ok(0) => break, // End of file.
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = dst.write_all_at(&buffer[..n], offset_out + copied) {
+            return Err(e);
+        }
+        copied += n as u64;
+    }
+    trace!("Copied {} bytes through userland", copied);
+    Ok(copied as usize)
+}
+
+// Translate a lock range from the kernel (inclusive end) into a struct flock fit for OFD locks.
+pub fn fuse_to_flock(typ: i32, start: u64, end: u64) -> libc::flock {
+    let mut lock : libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = typ as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = start as libc::off_t;
+    lock.l_len = if end >= OFFSET_MAX { 0 } else { (end - start + 1) as libc::off_t };
+    lock.l_pid = 0; // Mandatory for OFD locks.
+    lock
+}
+
+// And back: (start, end, type, pid) of a conflicting lock.
+pub fn flock_to_fuse(lock: &libc::flock) -> (u64, u64, i32, u32) {
+    let end = if lock.l_len == 0 { OFFSET_MAX } else { (lock.l_start + lock.l_len - 1) as u64 };
+    let pid = if lock.l_pid > 0 { lock.l_pid as u32 } else { 0 }; // OFD locks are not owned by any process.
+    (lock.l_start as u64, end, lock.l_type as i32, pid)
+}
+
+// rename(2) honouring the flags of renameat2(2) (RENAME_NOREPLACE, RENAME_EXCHANGE, RENAME_WHITEOUT).
+pub fn rename_with_flags(from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+    let (from, to) = match (CString::new(from.as_os_str().as_bytes()), CString::new(to.as_os_str().as_bytes())) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    // Through syscall(2) rather than the libc wrapper, which older C libraries lack.
+    if unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags as libc::c_uint
+        )
+    } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// _IOR('f', 1, long) and _IOW('f', 2, long), although the kernel reads and writes an int.
+pub const FS_IOC_GETFLAGS : libc::c_ulong = 0x80086601;
+pub const FS_IOC_SETFLAGS : libc::c_ulong = 0x40086602;
+// _IOR('X', 31, struct fsxattr): the kernel reads the attributes this way before it sets inode flags.
+pub const FS_IOC_FSGETXATTR : libc::c_ulong = 0x801c581f;
+pub const FSXATTR_SIZE : usize = 28;
+pub const FS_IMMUTABLE_FL : u32 = 0x00000010;
+pub const FS_APPEND_FL : u32 = 0x00000020;
+
+// Inode flags as chattr(1) sees them, through a file opened the way lsattr and chattr open it.
+fn open_for_flags(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW)
+        .open(path)
+}
+
+pub fn get_inode_flags(fd: RawFd) -> io::Result<u32> {
+    let mut flags : libc::c_int = 0;
+    if unsafe { libc::ioctl(fd, FS_IOC_GETFLAGS, &mut flags as *mut libc::c_int) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags as u32)
+}
+
+pub fn set_inode_flags(fd: RawFd, flags: u32) -> io::Result<()> {
+    let flags = flags as libc::c_int;
+    if unsafe { libc::ioctl(fd, FS_IOC_SETFLAGS, &flags as *const libc::c_int) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The struct fsxattr is passed on as it is.
+pub fn get_inode_fsxattr(fd: RawFd) -> io::Result<[u8; FSXATTR_SIZE]> {
+    let mut fsxattr = [0u8; FSXATTR_SIZE];
+    if unsafe { libc::ioctl(fd, FS_IOC_FSGETXATTR, fsxattr.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fsxattr)
+}
+
+pub fn get_path_fsxattr(path: &Path) -> io::Result<[u8; FSXATTR_SIZE]> {
+    match open_for_flags(path) {
+        Ok(file) => get_inode_fsxattr(file.as_raw_fd()),
+        Err(why) => Err(why),
+    }
+}
+
+pub fn get_path_flags(path: &Path) -> io::Result<u32> {
+    match open_for_flags(path) {
+        Ok(file) => get_inode_flags(file.as_raw_fd()),
+        Err(why) => Err(why),
+    }
+}
+
+pub fn set_path_flags(path: &Path, flags: u32) -> io::Result<()> {
+    match open_for_flags(path) {
+        Ok(file) => set_inode_flags(file.as_raw_fd(), flags),
+        Err(why) => Err(why),
+    }
+}
+
+// access(2) as the kernel would answer it for the current fs credentials (fsuid, fsgid, supplementary groups, capabilities), POSIX ACLs included.
+pub fn kernel_access(path: &Path, mask: u32) -> Result<(), i32> {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return Err(libc::EINVAL),
+    };
+    // faccessat2 is the only one to honour AT_EACCESS in the kernel rather than emulating it in the C library.
+    if unsafe { libc::syscall(libc::SYS_faccessat2, libc::AT_FDCWD, path.as_ptr(), mask as libc::c_int, libc::AT_EACCESS | libc::AT_SYMLINK_NOFOLLOW) } == -1 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    Ok(())
+}
+
+use fuser::Request;
+use libc::{EACCES, ENOSYS};
+use capabilities::Capability;
 use mirrorfs::MirrorFS;
 use std::ops::Shl;
 // Allows or denies access according to DAC (user/group permissions).
@@ -60,10 +450,35 @@ impl MirrorFS {
 				return Ok(());
 			}
 		}
-		
+
+		// UserMap restores the fsuid/fsgid by Dropping.
+		let user_token = self.userprelude(_req);
+
+		// Once the requesting user is embodied, the kernel gives the very answer it would give on the source.
+		let embodied = (uid == self.settings.uid || self.settings.has_cap(Capability::CAP_SETUID))
+					&& (gid == self.settings.gid || self.settings.has_cap(Capability::CAP_SETGID));
+		if embodied {
+			match kernel_access(path, _mask) {
+				Err(ENOSYS) => debug!("faccessat2 is not available: checking permission bits by ourselves."),
+				res => {
+					trace!("Kernel answered {:?} to access request {:b} as user {} on path {}", res, _mask, uid, path.display());
+					return res;
+				}
+			}
+		}
+
+		// Without ACLs, nor capabilities other than root's.
 		match path.symlink_metadata() {
 			Ok(md) => {
-					if uid == md.uid() {
+					if uid == 0 {
+					if _mask & libc::X_OK as u32 == 0 || md.is_dir() || md.permissions().mode() & 0o111 != 0 {
+						trace!("Access request {:b} as root on path {} is ok", _mask, path.display());
+						return Ok(());
+					} else {
+						trace!("Access request as root isn't ok! Nobody may execute {}", path.display());
+						return Err(EACCES);
+					}
+				} else if uid == md.uid() {
 					if md.permissions().mode() | _mask.shl(6) == md.permissions().mode() {
 						trace!("Access request {:b} as user {} on path {} is ok", _mask.shl(6), uid, path.display());
 						return Ok(());
@@ -71,7 +486,7 @@ impl MirrorFS {
 						trace!("Access request as user isn't ok! Request was {:b}, Permissions were {:b}", _mask.shl(6), md.permissions().mode());
 						return Err(EACCES);
 					}
-				} else if gid == md.gid() {
+				} else if gid == md.gid() || self.request_groups(_req).contains(&md.gid()) {
 					if md.permissions().mode() | _mask.shl(3) == md.permissions().mode() {
 						trace!("Access request {:b} as group member of {} on path {} is ok", _mask.shl(3), gid, path.display());
 						return Ok(());
@@ -97,3 +512,37 @@ impl MirrorFS {
 	}
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::{fuse_dirent_type, fuse_mode_type, path_attr};
+    use std::{env, fs, process};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use fuser::FileType;
+    use libc;
+
+    // Removed even when an assertion fails.
+    struct TempDir(PathBuf);
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn socket_types_are_sockets() {
+        assert_eq!(fuse_mode_type(libc::S_IFSOCK | 0o600), FileType::Socket);
+        assert_eq!(fuse_dirent_type(libc::DT_SOCK), Some(FileType::Socket));
+    }
+
+    #[test]
+    fn socket_nodes_are_sockets() {
+        let dir = TempDir(env::temp_dir().join(format!("mirrorfs-socket-{}", process::id())));
+        fs::create_dir(&dir.0).unwrap();
+        let socket = dir.0.join("socket");
+        let _listener = UnixListener::bind(&socket).unwrap();
+
+        assert_eq!(path_attr(&socket).unwrap().kind, FileType::Socket);
+    }
+}
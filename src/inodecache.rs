@@ -5,6 +5,7 @@ use std::sync::Mutex;
 use std::path;
 use std::time;
 use std::cmp::Ordering;
+use std::os::unix::fs::MetadataExt;
 use fasthashes::*;
 
 use filehandles::{Inode, HotFiles};
@@ -265,16 +266,50 @@ impl InodeCache {
         }
     }
     pub fn resolve(&self, ino: Inode) -> path::PathBuf {
-        let i = self.inode_mutex.lock().expect("This is not supposed to happen...");
-        if let Some(entry) = i.map.get(&ino) {
-            trace!("Learning from the cache : path {:?} for inode {}", entry.links.iter().next().unwrap().display(), ino);
-            entry.links.iter().next().unwrap().clone()
+        let links : Vec<path::PathBuf> = {
+            let i = self.inode_mutex.lock().expect("This is not supposed to happen...");
+            match i.map.get(&ino) {
+                Some(entry) => entry.links.iter().cloned().collect(),
+                None => {
+                    // This is to be improved...
+                    error!("This is not supposed to happen... Inode {} could not be found in the inode cache!\nDoes your application implement an internal inode cache ??", ino);
+                    return path::PathBuf::from("");
+                }
+            }
+        };
+        // With hard links, only trust a link that still leads to the inode on disk.
+        // Checked outside the lock, and only until a link passes: usually the first one does.
+        let valid = if links.len() > 1 {
+            links.iter().find(|link| match link.symlink_metadata() {
+                Ok(md) => md.ino() == ino,
+                Err(_) => false,
+            })
         } else {
-            // This is to be improved...
-            error!("This is not supposed to happen... Inode {} could not be found in the inode cache!\nDoes your application implement an internal inode cache ??", ino);
-            path::PathBuf::from("")
+            None
+        };
+        match valid.or(links.first()) {
+            Some(link) => {
+                trace!("Learning from the cache : path {:?} for inode {}", link.display(), ino);
+                link.clone()
+            },
+            None => {
+                debug!("Inode {} has no link left on disk: it only lives on through its open handles.", ino);
+                path::PathBuf::from("")
+            }
         }
     }
+    // An orphan was unlinked or replaced on disk while still open: only its handles lead to it.
+    pub fn is_orphan(&self, ino: Inode) -> bool {
+        let i = self.inode_mutex.lock().expect("This is not supposed to happen...");
+        match i.map.get(&ino) {
+            Some(entry) => entry.links.is_empty(),
+            None => false,
+        }
+    }
+    pub fn contains(&self, ino: Inode) -> bool {
+        let i = self.inode_mutex.lock().expect("This is not supposed to happen...");
+        i.map.contains_key(&ino)
+    }
     pub fn remove(&mut self, ino: Inode, link: Option<&path::Path>, pid: u32) {
         let mut i = self.inode_mutex.lock().expect("This is not supposed to happen...");
         let mut acc = 0;
@@ -293,7 +328,11 @@ impl InodeCache {
         } else {
             trace!("Removing link \"{}\" from cache for inode {}", link.unwrap().display(), ino);
             entry.links.remove(link.unwrap());
-            if entry.links.is_empty() {
+            if entry.links.is_empty() && self.hot_files.is_hot(ino) {
+                trace!("No more link associated to inode {}, but it is still open: keeping it in cache without any link.", ino);
+                i.map.insert(ino, entry);
+                self.total_size -= link.unwrap().as_os_str().len();
+            } else if entry.links.is_empty() {
                 trace!("No more link associated to inode {}, removing entry from cache.", ino);
                 i.journal[entry.index].ino = 0;
                 for p in entry.links.iter() {
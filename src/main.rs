@@ -1,7 +1,6 @@
 #![feature(stmt_expr_attributes)]
-extern crate fuse;
+extern crate fuser;
 extern crate libc;
-extern crate time;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -17,7 +16,6 @@ extern crate clap;
 extern crate capabilities;
 extern crate users;
 extern crate fnv;
-extern crate multimap;
 
 // Own modules
 mod mirrorfs;
@@ -26,21 +24,31 @@ mod helper;
 mod filehandles;
 mod user;
 mod fasthashes;
+mod perms;
 
+use std::process;
 use clap::{App, AppSettings};
 use slog::{DrainExt, Level, LevelFilter, Record};
 use capabilities::{Capabilities, Capability, Flag};
-use users::{get_current_uid, get_current_gid};
-#[cfg(feature="enable_unsecure_features")]
-use users::{get_user_by_name, get_group_by_name};
+use users::{get_current_uid, get_current_gid, get_user_by_name, get_group_by_name};
 // Own namespaces
 use mirrorfs::MirrorFS;
+use user::{CreateOwner, Squash};
+use perms::{AttrPolicy, ForcedAttrs, PermRule};
 #[cfg(feature="enable_unsecure_features")]
 use fasthashes::*;
+#[cfg(feature="enable_unsecure_features")]
+use user::IdRange;
 
-// Do not forget to have libfuse-dev and libcap-dev installed to compile on Linux!
+// Do not forget to have libcap-dev installed to compile on Linux (and fusermount to mount as a regular user)!
 
 
+// The logger prints from a thread of its own: replacing it waits for the reason of the failure to be printed.
+fn exit_failure() -> ! {
+	slog_scope::set_global_logger(slog::Logger::root(slog::Discard, o!()));
+	process::exit(1);
+}
+
 fn main () {
 	let cla = load_yaml!("cla.yml");
 	let args = App::from_yaml(cla)
@@ -84,6 +92,7 @@ fn main () {
 	let sysadmin_cap = caps.check(Capability::CAP_SYS_ADMIN, Flag::Permitted); // for setting file trusted and security xattr.
 	let mknod_cap = caps.check(Capability::CAP_MKNOD, Flag::Permitted); // for mknod only in case of neither regular file, nor FIFO, nor Unix domain socket
 	let dac_override_cap = caps.check(Capability::CAP_DAC_OVERRIDE, Flag::Permitted); // used by the "full-access" option
+	let linux_immutable_cap = caps.check(Capability::CAP_LINUX_IMMUTABLE, Flag::Permitted); // for the immutable and append-only flags chattr sets through ioctl.
 	let fsuid_cap = caps.check(Capability::CAP_SETUID, Flag::Permitted); // for every fs operation on the behalf of another user.
 	let fsgid_cap = caps.check(Capability::CAP_SETGID, Flag::Permitted); // for every fs operation on the behalf of another user.
 	//Keep only what's needed. The change of fsuid (in users.rs) will set the effective caps according to embodied user.
@@ -120,6 +129,10 @@ fn main () {
 	if dac_override_cap {
 		caps.update(&[Capability::CAP_DAC_OVERRIDE], Flag::Permitted, true);
 		caps.update(&[Capability::CAP_DAC_OVERRIDE], Flag::Effective, true);
+	}
+	if linux_immutable_cap {
+		// Only raised for root and the users given full access (see change_inode_flags).
+		caps.update(&[Capability::CAP_LINUX_IMMUTABLE], Flag::Permitted, true);
 	}
 	 //Apply the restricted Capability set.
 	let caps_res = caps.apply();
@@ -129,6 +142,103 @@ fn main () {
 		Err(why) => warn!("Could not drop capabilities... {:?} These are the capabilities permitted for the process {}", why, &new_caps),
 	}
 
+	// Every user goes through the mount (allow_other): someone has to check their permissions.
+	let default_permissions = args.is_present("defaultpermissions");
+	if default_permissions {
+		info!("Permissions are checked by the kernel.");
+	} else if !(fsuid_cap && fsgid_cap) {
+		error!("We lack the CAP_SETUID and CAP_SETGID capabilities to embody the requesting users, so every user would access the files as {}. Use the --default-permissions option to have the kernel check permissions instead.", get_current_uid());
+		exit_failure();
+	}
+
+	// NFS-like squashing onto the anonymous user and group.
+	let squash = if args.is_present("allsquash") {
+		Squash::All
+	} else if args.is_present("rootsquash") {
+		Squash::Root
+	} else {
+		Squash::Off
+	};
+	let anon_user = args.value_of("anonuid").unwrap(); // field has a default value, so unwrapping is safe
+	let anon_uid = match anon_user.parse() {
+		Ok(uid) => uid,
+		Err(_) => match get_user_by_name(anon_user) {
+			Some(u) => u.uid(),
+			None => {
+				error!("User name {} is not valid. Cannot squash to it.", anon_user);
+				exit_failure();
+			},
+		},
+	};
+	let anon_group = args.value_of("anongid").unwrap(); // field has a default value, so unwrapping is safe
+	let anon_gid = match anon_group.parse() {
+		Ok(gid) => gid,
+		Err(_) => match get_group_by_name(anon_group) {
+			Some(g) => g.gid(),
+			None => {
+				error!("Group name {} is not valid. Cannot squash to it.", anon_group);
+				exit_failure();
+			},
+		},
+	};
+	if squash != Squash::Off {
+		info!("Squashing {:?} onto uid {} and gid {}", squash, anon_uid, anon_gid);
+		if !(fsuid_cap && fsgid_cap) {
+			error!("We lack the CAP_SETUID and CAP_SETGID capabilities to embody the anonymous user, so squashed users would access the files as {}.", get_current_uid());
+			exit_failure();
+		}
+	}
+
+	// Owners and permissions shown whatever is on the source, as bindfs does.
+	let forced_uid = args.value_of("forceuser").map(|a_user| match a_user.parse() {
+		Ok(uid) => uid,
+		Err(_) => match get_user_by_name(a_user) {
+			Some(u) => u.uid(),
+			None => {
+				error!("User name {} is not valid. Cannot show files as owned by it.", a_user);
+				exit_failure();
+			},
+		},
+	});
+	let forced_gid = args.value_of("forcegroup").map(|a_group| match a_group.parse() {
+		Ok(gid) => gid,
+		Err(_) => match get_group_by_name(a_group) {
+			Some(g) => g.gid(),
+			None => {
+				error!("Group name {} is not valid. Cannot show files as owned by it.", a_group);
+				exit_failure();
+			},
+		},
+	});
+	let forced_perms = match args.value_of("perms").map(PermRule::parse_list) {
+		Some(Ok(rules)) => rules,
+		Some(Err(why)) => {
+			error!("Permission rules are not valid: {}", why);
+			exit_failure();
+		},
+		None => Vec::new(),
+	};
+	let forced = ForcedAttrs {
+		uid : forced_uid,
+		gid : forced_gid,
+		perms : forced_perms,
+		// fields have a default value among the possible ones, so unwrapping is safe
+		chmod : AttrPolicy::parse(args.value_of("chmodpolicy").unwrap()).unwrap(),
+		chown : AttrPolicy::parse(args.value_of("chownpolicy").unwrap()).unwrap(),
+	};
+
+	let create_owner = CreateOwner::parse(args.value_of("createas").unwrap()).unwrap(); // field has a default value among the possible ones, so unwrapping is safe
+	if create_owner != CreateOwner::Caller && !chown_cap {
+		error!("We lack the CAP_CHOWN capability. So files created will not be given to the {:?} in most cases!", create_owner);
+	}
+
+	// Owners and modes kept aside are only checked by the kernel.
+	let override_stat = args.is_present("overridestat");
+	if override_stat && !default_permissions {
+		error!("Owners and modes kept aside by the --override-stat option are only enforced with the --default-permissions option.");
+		exit_failure();
+	}
+
 	#[cfg(feature="enable_unsecure_features")] {
 		// Build optional map of users who may override DAC, thus getting full access to any file.
 		let mut fullaccess_set : FastSet<u32>;
@@ -212,6 +322,32 @@ fn main () {
 			}
 		}
 
+		// Build optional id ranges.
+		let mut uid_ranges = Vec::new();
+		if let Some(ranges) = args.values_of("uidrange") {
+			if !fsuid_cap {error!("We lack the CAP_SETUID capability. So uid shifting is likely to fail in most cases!");}
+			for range in ranges {
+				if let Some(r) = IdRange::parse(range) {
+					info!("Shifting {} uids from {} to {}", r.count, r.inside, r.outside);
+					uid_ranges.push(r);
+				} else {
+					error!("Uid range {} is not valid (INSIDE:OUTSIDE:COUNT expected). Not shifting it.", range);
+				}
+			}
+		}
+		let mut gid_ranges = Vec::new();
+		if let Some(ranges) = args.values_of("gidrange") {
+			if !fsgid_cap {error!("We lack the CAP_SETGID capability. So gid shifting is likely to fail in most cases!");}
+			for range in ranges {
+				if let Some(r) = IdRange::parse(range) {
+					info!("Shifting {} gids from {} to {}", r.count, r.inside, r.outside);
+					gid_ranges.push(r);
+				} else {
+					error!("Gid range {} is not valid (INSIDE:OUTSIDE:COUNT expected). Not shifting it.", range);
+				}
+			}
+		}
+
 		let fs = MirrorFS::new(
 			origin,
 			mountpoint,
@@ -219,8 +355,17 @@ fn main () {
 			get_current_gid(),
 			user_maps,
 			group_maps,
+			uid_ranges,
+			gid_ranges,
 			fullaccess_set,
-			new_caps
+			new_caps,
+			default_permissions,
+			squash,
+			anon_uid,
+			anon_gid,
+			forced,
+			create_owner,
+			override_stat
 		);
 		fs.mount(&mountpoint);
 	}
@@ -234,13 +379,26 @@ fn main () {
 		if args.is_present("groupmap") {
 			error!("The groupmap option is an unsecure option which has to be defined at compile time. Recompile with \"--features \"enable_unsecure_features\"\" to be able to use it!");
 		}
+		if args.is_present("uidrange") {
+			error!("The uidrange option is an unsecure option which has to be defined at compile time. Recompile with \"--features \"enable_unsecure_features\"\" to be able to use it!");
+		}
+		if args.is_present("gidrange") {
+			error!("The gidrange option is an unsecure option which has to be defined at compile time. Recompile with \"--features \"enable_unsecure_features\"\" to be able to use it!");
+		}
 
 		let fs = MirrorFS::new(
 			origin,
 			mountpoint,
 			get_current_uid(),
 			get_current_gid(),
-			new_caps
+			new_caps,
+			default_permissions,
+			squash,
+			anon_uid,
+			anon_gid,
+			forced,
+			create_owner,
+			override_stat
 		);
 		fs.mount(&mountpoint);
 	}
@@ -1,13 +1,13 @@
-use std::{fs, io};
+use std::{cmp, fs, io, thread};
 use std::path::{Path, PathBuf};
 use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
-use fuse::*;
-use time::Timespec;
-use libc::{c_int, ENOSYS, ERANGE, ENOENT, EEXIST, O_RDWR, O_RDONLY, O_WRONLY, O_APPEND, O_TRUNC};
+use fuser::*;
+use std::time::{Duration, SystemTime};
+use libc::{c_int, ENOSYS, ERANGE, ENOENT, EEXIST, EBADF, EPERM, ENOTTY, O_RDWR, O_RDONLY, O_WRONLY, O_APPEND, O_TRUNC};
 use libc;
-use multimap::MultiMap;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::mem;
 use std::ffi;
 use std::slice;
@@ -19,27 +19,48 @@ use capabilities::{Capabilities, Capability, Flag};
 use inodecache::*;
 use helper::*;
 use user::*;
+use filehandles::DirStream;
+use perms::ForcedAttrs;
 #[cfg(feature="enable_unsecure_features")]
 use fasthashes::*;
 
 // TODO : What is TTL by the way?
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
+const TTL: Duration = Duration::from_secs(1); // 1 second
 
 #[cfg(feature="enable_unsecure_features")]
 pub struct Settings {
 	pub uid : Uid,
 	pub gid : Gid,
 	pub fullaccess : FastSet<u32>,
-	// TODO: This implements process to disk mapping. Should we emplement reverse disk to process mapping too ?
+	// Process to disk mapping...
 	pub user_map : FastMap<Uid, Uid>,
 	pub group_map : FastMap<Gid, Gid>,
+	// ... and its reverse, disk to process mapping, for the attributes shown.
+	pub user_unmap : FastMap<Uid, Uid>,
+	pub group_unmap : FastMap<Gid, Gid>,
+	pub uid_ranges : Vec<IdRange>,
+	pub gid_ranges : Vec<IdRange>,
 	pub caps : Capabilities,
+	pub default_permissions : bool,
+	pub squash : Squash,
+	pub anon_uid : Uid,
+	pub anon_gid : Gid,
+	pub forced : ForcedAttrs,
+	pub create_owner : CreateOwner,
+	pub override_stat : bool,
 }
 #[cfg(not(feature="enable_unsecure_features"))]
 pub struct Settings {
 	pub uid : Uid,
 	pub gid : Gid,
 	pub caps : Capabilities,
+	pub default_permissions : bool,
+	pub squash : Squash,
+	pub anon_uid : Uid,
+	pub anon_gid : Gid,
+	pub forced : ForcedAttrs,
+	pub create_owner : CreateOwner,
+	pub override_stat : bool,
 }
 impl Settings {
 	pub fn has_cap(&self, cap: Capability) -> bool {
@@ -52,25 +73,36 @@ pub struct MirrorFS {
     virtual_path : String,
     // Use another hasher for efficency.
     inodes : InodeCache,
-    dentry_cache : MultiMap<u64, Vec<io::Result<fs::DirEntry>>>,
+    pub groups : GroupsCache,
     pub settings : Settings,
 }
 
 impl MirrorFS {
 	#[cfg(feature="enable_unsecure_features")]
-    pub fn new(base_path : &str, virtual_path : &str, uid: Uid, gid : Gid, user_map : FastMap<Uid, Uid>, group_map : FastMap<Gid, Gid>, fullaccess:FastSet<u32>, caps: Capabilities) -> MirrorFS {
+    pub fn new(base_path : &str, virtual_path : &str, uid: Uid, gid : Gid, user_map : FastMap<Uid, Uid>, group_map : FastMap<Gid, Gid>, uid_ranges : Vec<IdRange>, gid_ranges : Vec<IdRange>, fullaccess:FastSet<u32>, caps: Capabilities, default_permissions: bool, squash: Squash, anon_uid: Uid, anon_gid: Gid, forced: ForcedAttrs, create_owner: CreateOwner, override_stat: bool) -> MirrorFS {
         let mut fs = MirrorFS {
             base_path : base_path.to_owned(),
             virtual_path : virtual_path.to_owned(),
             inodes : InodeCache::new(10, 2),
-            dentry_cache : MultiMap::new(),
+            groups : GroupsCache::new(),
             settings : Settings {
 				uid : uid,
 				gid : gid,
 				fullaccess : fullaccess,
+				user_unmap : reverse_map(&user_map, "uid"),
+				group_unmap : reverse_map(&group_map, "gid"),
 				user_map: user_map,
 				group_map: group_map,
+				uid_ranges : uid_ranges,
+				gid_ranges : gid_ranges,
 				caps : caps,
+				default_permissions : default_permissions,
+				squash : squash,
+				anon_uid : anon_uid,
+				anon_gid : anon_gid,
+				forced : forced,
+				create_owner : create_owner,
+				override_stat : override_stat,
 			},
         };
         fs.inodes.store(1, &Path::new(base_path).to_path_buf());
@@ -79,16 +111,23 @@ impl MirrorFS {
         fs
     }
     #[cfg(not(feature="enable_unsecure_features"))]
-    pub fn new(base_path : &str, virtual_path : &str, uid: Uid, gid : Gid, caps: Capabilities) -> MirrorFS {
+    pub fn new(base_path : &str, virtual_path : &str, uid: Uid, gid : Gid, caps: Capabilities, default_permissions: bool, squash: Squash, anon_uid: Uid, anon_gid: Gid, forced: ForcedAttrs, create_owner: CreateOwner, override_stat: bool) -> MirrorFS {
         let mut fs = MirrorFS {
             base_path : base_path.to_owned(),
             virtual_path : virtual_path.to_owned(),
             inodes : InodeCache::new(10, 2),
-            dentry_cache : MultiMap::new(),
+            groups : GroupsCache::new(),
             settings : Settings {
 				uid : uid,
 				gid : gid,
 				caps : caps,
+				default_permissions : default_permissions,
+				squash : squash,
+				anon_uid : anon_uid,
+				anon_gid : anon_gid,
+				forced : forced,
+				create_owner : create_owner,
+				override_stat : override_stat,
 			},
         };
         fs.inodes.store(1, &Path::new(base_path).to_path_buf());
@@ -99,7 +138,11 @@ impl MirrorFS {
 
     pub fn mount<P: AsRef<Path>>(self, mountpoint : &P) {
 		// Mount options as if from the command line!
-        match mount(self, mountpoint, &["-oallow_other".as_ref()]) {
+		let mut options = vec![MountOption::AllowOther];
+		if self.settings.default_permissions {
+			options.push(MountOption::DefaultPermissions);
+		}
+        match mount2(self, mountpoint, &options) {
 			Ok(_) => trace!("Filesystem unmounted successfully."),
 			Err(e) => error!("Filesystem return error {:?}", e),
 		}
@@ -121,22 +164,180 @@ impl MirrorFS {
         // For forward compatibility: returning a Result will probably have some use later on.
         Ok(original.join(name))
     }
+
+    /// chattr(1) flags, set under the caller's credentials: the kernel only lets CAP_LINUX_IMMUTABLE holders touch the immutable and append-only ones.
+    fn change_inode_flags (&self, _req: &Request, path: &Path, orphan_fd: Option<RawFd>, flags: u32) -> Result<(), i32> {
+        let old_flags = match orphan_fd {
+            Some(fd) => get_inode_flags(fd),
+            None => get_path_flags(path),
+        };
+        let old_flags = match old_flags {
+            Ok(old_flags) => old_flags,
+            Err(why) => {
+                warn!("Could not get inode flags of {} : {:?}", path.display(), why);
+                return Err(why.raw_os_error().unwrap());
+            }
+        };
+        // The capability is the fuse implementation's, not the caller's: only root and the users given full access get it, as on a local filesystem.
+        let _cap_token = if (old_flags ^ flags) & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            if !self.may_protect(_req) {
+                warn!("Cannot change the immutable or append-only flag of {} : only root and the users given full access may", path.display());
+                return Err(EPERM);
+            }
+            if !self.settings.has_cap(Capability::CAP_LINUX_IMMUTABLE) {
+                warn!("Cannot change the immutable or append-only flag of {} : you need CAP_LINUX_IMMUTABLE on the fuse implementation", path.display());
+                return Err(EPERM);
+            }
+            Some(self.set_cap(&[Capability::CAP_LINUX_IMMUTABLE]))
+        } else {
+            None
+        };
+        let res = match orphan_fd {
+            Some(fd) => set_inode_flags(fd, flags),
+            None => set_path_flags(path, flags),
+        };
+        match res {
+            Ok(_) => {
+                trace!("Set inode flags of {} from {:x} to {:x}", path.display(), old_flags, flags);
+                Ok(())
+            },
+            Err(why) => {
+                warn!("Could not set inode flags {:x} on {} : {:?}", flags, path.display(), why);
+                Err(why.raw_os_error().unwrap())
+            }
+        }
+    }
+
+    /// Inode of the parent of a directory, as seen from inside the mirror: the root's parent is the mount point's.
+    fn parent_ino (&self, ino: u64) -> u64 {
+        let parent = if ino == 1 {
+            match Path::new(&self.virtual_path).parent() {
+                Some(parent) if parent.as_os_str().is_empty() => PathBuf::from("."),
+                Some(parent) => parent.to_path_buf(),
+                None => return 1, // Mounted on "/".
+            }
+        } else {
+            match self.inodes.resolve(ino).parent() {
+                Some(parent) if parent == Path::new(&self.base_path) => return 1,
+                Some(parent) => parent.to_path_buf(),
+                None => return ino,
+            }
+        };
+        match parent.symlink_metadata() {
+            Ok(md) => md.ino(),
+            Err(why) => {
+                warn!("Could not get the inode of {}, parent of inode {} : {:?}", parent.display(), ino, why);
+                ino
+            }
+        }
+    }
+
+    /// Shows the owners, mode and device number an unprivileged mirror keeps aside for path (or its open file), if any.
+    fn apply_override_stat (&self, attr: &mut FileAttr, path: &Path, fd: Option<RawFd>) {
+        if !self.settings.override_stat {
+            return;
+        }
+        match get_override_stat(path, fd) {
+            Ok(Some(stat)) => {
+                trace!("Overriding attributes of inode {} with {:?}", attr.ino, stat);
+                stat.apply(attr);
+            },
+            Ok(None) => {},
+            Err(why) => warn!("Could not read the overridden attributes of {} : {:?}", path.display(), why),
+        }
+    }
+
+    /// The file type a listing shows for path: the overridden one, if any.
+    fn override_kind (&self, kind: FileType, path: &Path) -> FileType {
+        if !self.settings.override_stat {
+            return kind;
+        }
+        match get_override_stat(path, None) {
+            Ok(Some(stat)) => fuse_mode_type(stat.mode as libc::mode_t),
+            Ok(None) => kind,
+            Err(why) => {
+                warn!("Could not read the overridden attributes of {} : {:?}", path.display(), why);
+                kind
+            }
+        }
+    }
+
+    /// Attributes of path, or of its open file fd, overrides included.
+    fn stat (&self, path: &Path, fd: Option<RawFd>) -> io::Result<FileAttr> {
+        let attr = match fd {
+            Some(fd) => fd_attr(fd),
+            None => path_attr(path),
+        };
+        match attr {
+            Ok(mut attr) => {
+                self.apply_override_stat(&mut attr, path, fd);
+                Ok(attr)
+            },
+            Err(why) => Err(why),
+        }
+    }
+
+    /// Gives a file just created in parent the owners the creation policy wants, by fd if name is empty. This is done under our own credentials: chown takes CAP_CHOWN.
+    fn own_new (&self, _req: &Request, parent: &Path, fd: RawFd, name: &Path, mut attr: FileAttr) -> FileAttr {
+        let (uid, gid) = match self.settings.create_owner {
+            // Once embodied, the kernel already gave it to the caller, or to the group of a setgid parent.
+            CreateOwner::Caller => {
+                let (uid, gid) = self.usermap(_req);
+                (if self.settings.has_cap(Capability::CAP_SETUID) { attr.uid } else { uid },
+                 if self.settings.has_cap(Capability::CAP_SETGID) { attr.gid } else { gid })
+            },
+            CreateOwner::Mounter => (self.settings.uid, self.settings.gid),
+            CreateOwner::Parent => match path_attr(parent) {
+                Ok(parent_attr) => (parent_attr.uid, parent_attr.gid),
+                Err(why) => {
+                    warn!("Could not get the owners of {} for inode {} to inherit them : {:?}", parent.display(), attr.ino, why);
+                    return attr;
+                }
+            },
+        };
+        if uid == attr.uid && gid == attr.gid {
+            return attr;
+        }
+        if !self.settings.has_cap(Capability::CAP_CHOWN) {
+            trace!("Cannot give inode {} to uid {} and gid {} (you need CAP_CHOWN on the fuse implementation)", attr.ino, uid, gid);
+            return attr;
+        }
+        let c_name = ffi::CString::new(name.as_os_str().as_bytes()).unwrap();
+        let flags = if name.as_os_str().is_empty() { libc::AT_EMPTY_PATH } else { libc::AT_SYMLINK_NOFOLLOW };
+        if unsafe { libc::fchownat(fd, c_name.as_ptr(), uid as libc::uid_t, gid as libc::gid_t, flags) } == -1 {
+            let e = nix::errno::errno();
+            warn!("Could not give inode {} to uid {} and gid {} : error {}", attr.ino, uid, gid, e);
+        } else {
+            trace!("Gave inode {} to uid {} and gid {}", attr.ino, uid, gid);
+            attr.uid = uid;
+            attr.gid = gid;
+        }
+        attr
+    }
 }
 
 /* TODO :
-- figure out what fsyncdir() is, how it is to be implemented and whether it is to be implemented.
-- getlk ?
+- fuser does not dispatch FUSE_TMPFILE: O_TMPFILE opens fail with EOPNOTSUPP.
+- flock(2) locks stay within the kernel of the mount: fuser does not tell them apart from POSIX record locks (FUSE_LK_FLOCK), so FUSE_FLOCK_LOCKS is not negotiated and the source does not see them.
 - race conditions before ReplyEntry...
 - could we avoid copying some pathbufs ?
 */
 impl Filesystem for MirrorFS {
-    fn init(&mut self, _req: &Request) -> Result<(), c_int> {
+    fn init(&mut self, _req: &Request, _config: &mut KernelConfig) -> Result<(), c_int> {
         info!{"MirrorFS was initialized !"};
+        // Otherwise the kernel keeps fcntl record locks to itself, and they never reach the source.
+        if let Err(missing) = _config.add_capabilities(consts::FUSE_POSIX_LOCKS) {
+            warn!("The kernel does not forward POSIX record locks (missing capabilities {:x}): they will not be seen on the source", missing);
+        }
+        // Listings then come with the attributes of their entries, sparing a lookup per entry.
+        if let Err(missing) = _config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO) {
+            debug!("The kernel does not do readdirplus (missing capabilities {:x})", missing);
+        }
         // spawn_mount other FS.
         Ok(())
     }
 
-    fn destroy (&mut self, _req: &Request) {
+    fn destroy (&mut self) {
         info!("MirrorFS was unmounted, and is now about to be destroyed!");
         //unmount other FS.
     }
@@ -158,12 +359,11 @@ impl Filesystem for MirrorFS {
                 return;
             }
         };
-        // symlink_metadata avoids "dereferencing" symlinks : otherwise, metadata() would yield the metadata of the link's target, of course.
-        match path_base.symlink_metadata() {
-            Ok(md) => {
-				self.inodes.store(md.ino(), &path_base);
-				let attr : FileAttr = fill_file_attr(&md);
-				reply.entry(&TTL, &attr, 0);
+        // path_attr does not "dereference" symlinks : otherwise, we would yield the attributes of the link's target, of course.
+        match self.stat(&path_base, None) {
+            Ok(attr) => {
+				self.inodes.store(attr.ino, &path_base);
+				reply.entry(&TTL, &self.present(attr), 0);
 			},
              Err(error) => {
                  warn!("Could not lookup {} : {:?}", path_base.display(), error);
@@ -178,7 +378,7 @@ impl Filesystem for MirrorFS {
         self.inodes.remove(_ino, None, _req.pid());
     }
 
-    fn mkdir (&mut self, _req: &Request, parent: u64, _name: &ffi::OsStr, _mode: u32, reply: ReplyEntry) {
+    fn mkdir (&mut self, _req: &Request, parent: u64, _name: &ffi::OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
 		let name = Path::new(_name);
         let to_create = match self.name2original(name, parent) {
             Ok(path) => path,
@@ -195,14 +395,14 @@ impl Filesystem for MirrorFS {
         {
             Ok(_) => {
                 trace!("Successfully created directory {}", to_create.display());
-                match to_create.symlink_metadata() {
-                    Ok(md) => {
-                        self.inodes.store(md.ino(), &to_create);
+                match path_attr(&to_create) {
+                    Ok(attr) => {
+                        drop(user_token);
+                        let attr = self.own_new(_req, to_create.parent().unwrap(), libc::AT_FDCWD, &to_create, attr);
+                        self.inodes.store(attr.ino, &to_create);
                         reply.entry(
                             &TTL,
-                            &fill_file_attr(&to_create
-                                            .symlink_metadata()
-                                            .unwrap()),
+                            &self.present(attr),
                             0
                         );
                     },
@@ -253,110 +453,85 @@ impl Filesystem for MirrorFS {
         }
     }
 
-    fn opendir (&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        // This is only useful to prevent the inodecache from forgetting some hot inode.
-        trace!("Made handle to directory {}", self.inodes.resolve(_ino).display());
+    fn opendir (&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
+        let path = self.inodes.resolve(_ino);
 
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
-        reply.opened(self.inodes.hot_files.make_handle(None, _ino), _flags);
+        // Each handle gets its own stream, which readdir reads lazily and seeks through by offset.
+        match DirStream::open(&path) {
+            Ok(stream) => {
+                let fh = self.inodes.hot_files.make_dir_handle(stream, _ino);
+                trace!("Made handle {} to directory {}", fh, path.display());
+                reply.opened(fh, _flags as u32);
+            },
+            Err(why) => {
+                warn!("Could not open directory {} : {:?}", path.display(), why);
+                reply.error(why.raw_os_error().unwrap());
+            }
+        }
     }
 
-    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
-		use std::os::unix::fs::DirEntryExt;
+    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
         trace!("fn readdir for ino {}, at offset {}", ino, offset);
 
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
-        if offset > 0 {
-            if let Some(mut list) = self.dentry_cache.remove(&ino) {
-                let list = list.remove(0);
-                // offset - 1 instead of + 1 is for the "." and ".." added dentries that aren't in the cache. So offset + 1 - 2...
-                if list.len() == {offset - 1} as usize {
-                    // We're done!
-                    trace!("Finalising readdir transaction with the kernel for ino {}.", ino);
-                    reply.ok();
-                    return;
-                } else {
-                    trace!("Wow this directory is huge!!! (or your buffer is tiny...)");
-                    self.dentry_cache.insert(ino, list);
-                }
-            } else {
-                warn!("This is not supposed to happen : kernel is asking for offset > 0, but dentries are not in the cache... Replying Error to the kernel...");
-                // For some uncanny reason, the kernel keeps asking for ino 1 a lot of times at program startup, then stops. So this case actually happens...
-                reply.error(ENOENT);
+        let mut stream = match self.inodes.hot_files.take_dir(_fh) {
+            Some(stream) => stream,
+            None => {
+                warn!("Handle {} to directory {} has no directory stream", _fh, ino);
+                reply.error(EBADF);
                 return;
             }
-        }
-         if ! self.dentry_cache.contains_key(&ino) {
-             trace!("Dentries for ino {} are not in the readdir cache. So let's get them from the disk.", ino);
-             match fs::read_dir(self.inodes.resolve(ino)) {
-                 // Found dir entries !
-                 Ok(dentries) => {
-                     self.dentry_cache.insert(ino, dentries.collect::<Vec<_>>());
-                     trace!("Added dentries for ino {} in readdir cache.", ino);
-                 },
-                 // Path is invalid or protected?
-                 Err(e)=> {
-                     error!("{:?}",e);
-                     reply.error(e.raw_os_error().unwrap());
-                     return
-                 },
-             }
-         }
-        // Now get dentries from the cache.
-        let dentries = match self.dentry_cache.get_mut(&ino) {
-            Some(dentries) => dentries,
-            None => {
-                    trace!("Couldn't get dentries from readdir cache for ino {}",ino);
-                    reply.error(ENOENT);
-                    return
-                },
-            };
-        // Now we can (re)start sending dentries to the kernel.
-        reply.add(ino, 0, FileType::Directory, ".");
-        reply.add(ino, 1, FileType::Directory, "..");// TODO : should we bother getting the parent's inode ?
+        };
+        // Offsets are telldir cookies, so entries added or removed meanwhile do not shift the listing.
+        stream.seek(offset as u64);
+        let dir_path = self.inodes.resolve(ino);
         let mut count = 0;
-        while count < dentries.len() {
-            match dentries[count] {
-                Ok(ref dentry) => {
-					match dentry.file_type() {
-						Ok(file_type) => {
-							trace!("adding {:?} to reply with ino {} and offset {}", dentry.file_name(), dentry.ino(), count);
-							// count + 2 is for "." and ".." dentries added.
-							if reply.add(dentry.ino(),
-								 {count + 2} as u64,
-								 fuse_file_type(file_type),
-								 dentry.file_name()) {
-									 trace!("DirEntry buffer filled! Breaking : waiting for kernel to call back and take the rest of the dentries...");
-									 break;
-							}
-						},
-						Err(why) => {
-							error!("Could not get file type of {:?} : {:?}\n We're forced to skip this entry because there is no way to reply an unknown file type to the request.", dentry.file_name(), why);
-							break;
-						}
-					}
-
+        loop {
+            let dentry = match stream.next_entry() {
+                Some(Ok(dentry)) => dentry,
+                Some(Err(why)) => {
+                    warn!("Could not read an entry of directory {} : {:?}", ino, why);
+                    if count == 0 {
+                        self.inodes.hot_files.restore_dir(_fh, stream);
+                        reply.error(why.raw_os_error().unwrap());
+                        return;
+                    }
+                    break;
                 },
-                Err(ref e) => println!("{:?}", e),
+                None => break,
+            };
+            let (dentry_ino, dentry_kind) = if dentry.name == "." {
+                (ino, dentry.kind)
+            } else if dentry.name == ".." {
+                (self.parent_ino(ino), dentry.kind)
+            } else {
+                (dentry.ino, self.override_kind(dentry.kind, &dir_path.join(&dentry.name)))
+            };
+            trace!("adding {:?} to reply with ino {} and offset {}", dentry.name, dentry_ino, dentry.offset);
+            if reply.add(dentry_ino, dentry.offset as i64, dentry_kind, &dentry.name) {
+                trace!("DirEntry buffer filled! Breaking : waiting for kernel to call back and take the rest of the dentries...");
+                break;
             }
             count += 1;
         }
+        self.inodes.hot_files.restore_dir(_fh, stream);
         debug!("Filled DirEntry buffer. Now Sending to the kernel.");
         reply.ok();
      }
 
-    fn releasedir (&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
+    fn releasedir (&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: i32, reply: ReplyEmpty) {
          // Quite straightforward as of now!
          self.inodes.hot_files.release_handle(_fh);
          trace!("Released handle {} to directory {} (inode={})", _fh, self.inodes.resolve(_ino).display(), _ino);
          reply.ok();
     }
 
-    fn open (&mut self, _req: &Request, _ino: u64, flags: u32, reply: ReplyOpen) {
+    fn open (&mut self, _req: &Request, _ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("open callback for ino {} and flags {}", _ino, flags);
 
         // UserMap restores the fsuid/fsgid by Dropping.
@@ -364,15 +539,15 @@ impl Filesystem for MirrorFS {
 
         let path = self.inodes.resolve(_ino);
         let read_f =
-                   flags | O_RDWR as u32 == flags
+                   flags | O_RDWR == flags
                    ||
-                   flags | O_RDONLY as u32 == flags;
+                   flags | O_RDONLY == flags;
         let write_f =
-                   flags | O_RDWR as u32 == flags
+                   flags | O_RDWR == flags
                    ||
-                   flags | O_WRONLY as u32 == flags;
-        let append_f = flags | O_APPEND as u32 == flags;
-        let truncate_f = flags | O_TRUNC as u32 == flags;
+                   flags | O_WRONLY == flags;
+        let append_f = flags | O_APPEND == flags;
+        let truncate_f = flags | O_TRUNC == flags;
 
         match fs::OpenOptions::new()
                         .read(read_f)
@@ -382,7 +557,7 @@ impl Filesystem for MirrorFS {
                         .open(&path) {
              Ok(file) => {
                  trace!("Opened successfully {} with read={}, write={}, append={} and truncate={}", path.display(), read_f, write_f, append_f, truncate_f);
-                 reply.opened(self.inodes.hot_files.make_handle(Some(file), _ino), flags);
+                 reply.opened(self.inodes.hot_files.make_handle(Some(file), _ino), flags as u32);
              }
              Err(why) => {
                  warn!("Could not open file {} with read={}, write={}, append={} and truncate={} : {:?}", path.display(), read_f, write_f, append_f, truncate_f, why);
@@ -391,7 +566,7 @@ impl Filesystem for MirrorFS {
          }
      }
 
-    fn read (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, _size: u32, reply: ReplyData) {
+    fn read (&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, _size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
         debug!("read callback for ino {} and file handle {}, at offset {} for the size of {}", ino, _fh, offset, _size);
 
         // UserMap restores the fsuid/fsgid by Dropping.
@@ -400,7 +575,7 @@ impl Filesystem for MirrorFS {
         let mut buffer = Vec::with_capacity(_size as usize);
         buffer.resize(_size as usize, 0);
         let mut file = self.inodes.hot_files.take_file(_fh);
-        if let Err(why) = file.seek(SeekFrom::Start(offset)) {
+        if let Err(why) = file.seek(SeekFrom::Start(offset as u64)) {
             error!("Ominous error while seeking to offset {} of ino {} : {:?}", offset, ino, why);
             self.inodes.hot_files.restore_file(_fh, file, ino);
             reply.error(why.raw_os_error().unwrap());
@@ -433,13 +608,13 @@ impl Filesystem for MirrorFS {
         debug!("Successfully sent buffer of {} bytes to the kernel", buffer.len());
     }
 
-    fn write (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+    fn write (&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
 
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
         let mut file = self.inodes.hot_files.take_file(_fh);
-        if let Err(why) = file.seek(SeekFrom::Start(offset)) {
+        if let Err(why) = file.seek(SeekFrom::Start(offset as u64)) {
             error!("Ominous error while seeking to offset {} of ino {} : {:?}", offset, ino, why);
             self.inodes.hot_files.restore_file(_fh, file, ino);
             reply.error(why.raw_os_error().unwrap());
@@ -463,6 +638,8 @@ impl Filesystem for MirrorFS {
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
+        // Like close(2), flushing drops the POSIX record locks of the lock owner.
+        self.inodes.hot_files.release_locks(_ino, _lock_owner);
         let mut file = self.inodes.hot_files.take_file(_fh);
         match file.flush() {
             Ok(_) => {
@@ -477,7 +654,7 @@ impl Filesystem for MirrorFS {
         self.inodes.hot_files.restore_file(_fh, file, _ino);
     }
 
-    fn create (&mut self, _req: &Request, parent: u64, _name: &ffi::OsStr, _mode: u32, flags: u32, reply: ReplyCreate) {
+    fn create (&mut self, _req: &Request, parent: u64, _name: &ffi::OsStr, _mode: u32, _umask: u32, flags: i32, reply: ReplyCreate) {
 		let name = Path::new(_name);
         let to_create = match self.name2original(name, parent) {
             Ok(path) => path,
@@ -491,15 +668,15 @@ impl Filesystem for MirrorFS {
         let user_token = self.userprelude(_req);
 
         let read_f =
-                   flags | O_RDWR as u32 == flags
+                   flags | O_RDWR == flags
                    ||
-                   flags | O_RDONLY as u32 == flags;
+                   flags | O_RDONLY == flags;
         let write_f =
-                   flags | O_RDWR as u32 == flags
+                   flags | O_RDWR == flags
                    ||
-                   flags | O_WRONLY as u32 == flags;
-		let append_f = flags | O_APPEND as u32 == flags;
-		let truncate_f = flags | O_TRUNC as u32 == flags;
+                   flags | O_WRONLY == flags;
+		let append_f = flags | O_APPEND == flags;
+		let truncate_f = flags | O_TRUNC == flags;
         match fs::OpenOptions::new()
                        .read(read_f)
                        .write(write_f)
@@ -508,25 +685,27 @@ impl Filesystem for MirrorFS {
                        .create_new(true)
                        .open(&to_create) {
             Ok(file) => {
-                let md = match file.metadata() {
-                    Ok(md) => md,
+                let attr = match fd_attr(file.as_raw_fd()) {
+                    Ok(attr) => attr,
                     Err(why) => {
                         warn!("Newly created file {} was probably racily removed : {:?}", to_create.display(), why);
                         reply.error(why.raw_os_error().unwrap());
                         return;
                     },
                 };
-                let ino = md.ino();
+                drop(user_token);
+                let attr = self.own_new(_req, to_create.parent().unwrap(), file.as_raw_fd(), Path::new(""), attr);
+                let ino = attr.ino;
                 self.inodes.store(ino, &to_create);
                 // store it into the fh cache too.
 
                 trace!("Successfully created file {} with read={}, write={}, append={} and truncate={}", to_create.display(), read_f, write_f, append_f, truncate_f);
                 reply.created(
                     &TTL,
-                    &fill_file_attr(&md),
+                    &self.present(attr),
                     0, // Generation?
                     self.inodes.hot_files.make_handle(Some(file), ino),
-                    flags
+                    flags as u32
                 );
             },
             Err(why) => {
@@ -536,14 +715,14 @@ impl Filesystem for MirrorFS {
         }
     }
 
-    fn release (&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
+    fn release (&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
         // Quite straightforward as of now!
         self.inodes.hot_files.release_handle(_fh);
         trace!("Released handle {} to file {} (inode={})", _fh, self.inodes.resolve(_ino).display(), _ino);
         reply.ok();
     }
 
-    fn rename (&mut self, _req: &Request, _parent: u64, _name: &ffi::OsStr, _newparent: u64, _newname: &ffi::OsStr, reply: ReplyEmpty) {
+    fn rename (&mut self, _req: &Request, _parent: u64, _name: &ffi::OsStr, _newparent: u64, _newname: &ffi::OsStr, flags: u32, reply: ReplyEmpty) {
 		let name = Path::new(_name);
         let old_path = match self.name2original(name, _parent) {
             Ok(path) => path,
@@ -564,24 +743,41 @@ impl Filesystem for MirrorFS {
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
+        let ino = match old_path.symlink_metadata() {
+            Ok(md) => md.ino(),
+            Err(why) => {
+                warn!("Could not rename {} into {} : {:?}", old_path.display(), new_path.display(), why);
+                reply.error(why.raw_os_error().unwrap());
+                return;
+            }
+        };
+        // Whatever new_path points to gets either overwritten or, when exchanging, moved to old_path.
+        let victim = new_path.symlink_metadata().ok().map(|md| md.ino());
 
-        match fs::rename(&old_path, &new_path) {
+        match rename_with_flags(&old_path, &new_path, flags) {
             Ok(_) => {
-                match new_path.symlink_metadata() {
-                    Ok(md) => {
-                        trace!("Successfully renamed {} to {}", old_path.display(), new_path.display());
-                        self.inodes.remove(md.ino(), Some(&old_path), 0);
-                        self.inodes.store(md.ino(), &new_path);
-                        reply.ok();
-                    },
-                    Err(why) => {
-                        warn!("Path renamed from {} into {} could not be queried for metadata : {:?}", old_path.display(), new_path.display(), why);
-                        reply.error(why.raw_os_error().unwrap());
+                trace!("Successfully renamed {} to {} with flags {:x}", old_path.display(), new_path.display(), flags);
+                if victim == Some(ino) {
+                    // Both names were links to the same inode: the kernel leaves them as they are.
+                    trace!("{} and {} are the same inode {}, nothing changed.", old_path.display(), new_path.display(), ino);
+                    reply.ok();
+                    return;
+                }
+                self.inodes.remove(ino, Some(&old_path), 0);
+                // A victim the kernel never looked up is unknown to the cache, and stays so.
+                if let Some(victim) = victim.filter(|victim| self.inodes.contains(*victim)) {
+                    // An overwritten victim that is still open stays reachable through its handles.
+                    self.inodes.remove(victim, Some(&new_path), 0);
+                    if flags & libc::RENAME_EXCHANGE as u32 != 0 {
+                        trace!("Exchanged inode {} now lives at {}", victim, old_path.display());
+                        self.inodes.store(victim, &old_path);
                     }
                 }
+                self.inodes.store(ino, &new_path);
+                reply.ok();
             },
             Err(why) => {
-                warn!("Could not rename {} into {} : {:?}", old_path.display(), new_path.display(), why);
+                warn!("Could not rename {} into {} with flags {:x} : {:?}", old_path.display(), new_path.display(), flags, why);
                 reply.error(why.raw_os_error().unwrap());
             }
         }
@@ -608,11 +804,15 @@ impl Filesystem for MirrorFS {
         match fs::hard_link(&first_path, &next_path) {
             Ok(_) => {
                 trace!("Successfully created link {} based on {}", next_path.display(), first_path.display());
-                match next_path.symlink_metadata() {
-                    Ok(md) => reply.entry(&TTL,
-                        &fill_file_attr(&md),
-                        0
-                    ),
+                match self.stat(&next_path, None) {
+                    Ok(attr) => {
+                        // One more link for the same inode.
+                        self.inodes.store(attr.ino, &next_path);
+                        reply.entry(&TTL,
+                            &self.present(attr),
+                            0
+                        );
+                    },
                     Err(what) => {
                         warn!("It seems the link just created ({}) could not be queried for metadata. Was it removed in an race condition ? : {:?}", next_path.display(), what);
                         reply.error(what.raw_os_error().unwrap());
@@ -650,6 +850,10 @@ impl Filesystem for MirrorFS {
         match fs::remove_file(&file) {
             Ok(_) => {
                 trace!("Successfully removed file {}", &file.display());
+                if md.nlink() > 1 {
+                    trace!("Inode {} survives through {} other link(s).", md.ino(), md.nlink() - 1);
+                }
+                // Only this link goes away: the inode keeps resolving through the links left.
                 self.inodes.remove(md.ino(), Some(&file), 0);
                 reply.ok();
             },
@@ -660,7 +864,7 @@ impl Filesystem for MirrorFS {
         }
     }
 
-    fn mknod (&mut self, _req: &Request, parent: u64, _name: &ffi::OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+    fn mknod (&mut self, _req: &Request, parent: u64, _name: &ffi::OsStr, _mode: u32, _umask: u32, _rdev: u32, reply: ReplyEntry) {
         use nix::sys::stat;
 
         let name = Path::new(_name);
@@ -675,17 +879,39 @@ impl Filesystem for MirrorFS {
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
-        let kind = stat::SFlag::from_bits_truncate(_mode as libc::mode_t);
+        // Regular files, FIFOs and Unix domain sockets need no CAP_MKNOD, unlike devices.
+        // Unprivileged mirrors create regular files instead, and keep the type and number of the device aside.
+        let file_type = _mode as libc::mode_t & libc::S_IFMT;
+        let emulated = self.settings.override_stat && !self.settings.has_cap(Capability::CAP_MKNOD)
+                       && (file_type == libc::S_IFCHR || file_type == libc::S_IFBLK);
+        let kind = stat::SFlag::from_bits_truncate(if emulated { libc::S_IFREG } else { file_type });
         let perm = stat::Mode::from_bits_truncate(_mode as libc::mode_t);
 
-        match stat::mknod(&node, kind, perm, _rdev as nix::sys::stat::dev_t) {
+        match stat::mknod(&node, kind, perm, if emulated { 0 } else { _rdev as nix::sys::stat::dev_t }) {
             Ok(_) => {
                 trace!("Successfully created node {} as a {:?} with permissions {:?}", node.display(), kind, perm);
-                match node.symlink_metadata() {
-                    Ok(md) => {
-                        self.inodes.store(md.ino(), &node);
+                match path_attr(&node) {
+                    Ok(attr) => {
+                        drop(user_token);
+                        let mut attr = self.own_new(_req, node.parent().unwrap(), libc::AT_FDCWD, &node, attr);
+                        if emulated {
+                            let device = OverrideStat {
+                                uid : attr.uid,
+                                gid : attr.gid,
+                                mode : _mode,
+                                rdev : _rdev,
+                            };
+                            if let Err(why) = set_override_stat(&node, None, &device) {
+                                warn!("Could not keep {:?} aside for node {} : {:?}", device, node.display(), why);
+                                let _ = fs::remove_file(&node);
+                                reply.error(why.raw_os_error().unwrap());
+                                return;
+                            }
+                            device.apply(&mut attr);
+                        }
+                        self.inodes.store(attr.ino, &node);
                         reply.entry(&TTL,
-                        &fill_file_attr(&md),
+                        &self.present(attr),
                         0// Generation?
                         );
                     },
@@ -697,21 +923,30 @@ impl Filesystem for MirrorFS {
             }
             Err(why) => {
                 warn!("Could not create node {} as a {:?} with permissions {:?} : {:?}", node.display(), kind, perm, why);
-                reply.error(why.errno() as i32);
+                reply.error(why as i32);
             }
         }
     }
 
-    fn getattr (&mut self, _req: &Request, _ino: u64, reply: ReplyAttr) {
+    fn getattr (&mut self, _req: &Request, _ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         let path = self.inodes.resolve(_ino);
 
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
-        match path.symlink_metadata() {
-            Ok(md) => {
+        let attr = if self.inodes.is_orphan(_ino) {
+            trace!("Getting attributes of inode {} through its open handles", _ino);
+            match self.inodes.hot_files.fd(_ino) {
+                Some(fd) => self.stat(&path, Some(fd)),
+                None => self.inodes.hot_files.attr(_ino),
+            }
+        } else {
+            self.stat(&path, None)
+        };
+        match attr {
+            Ok(attr) => {
                 trace!("Successfully got attributes for {}", path.display());
-                reply.attr(&TTL, &fill_file_attr(&md));
+                reply.attr(&TTL, &self.present(attr));
             }
             Err(why) => {
                 warn!("Could not get attributes for {} : {:?}", path.display(), why);
@@ -720,67 +955,168 @@ impl Filesystem for MirrorFS {
         }
     }
 
-    fn setattr (&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+    fn setattr (&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<TimeOrNow>, _mtime: Option<TimeOrNow>, _ctime: Option<SystemTime>, _fh: Option<u64>, _crtime: Option<SystemTime>, _chgtime: Option<SystemTime>, _bkuptime: Option<SystemTime>, _flags: Option<u32>, reply: ReplyAttr) {
         let path = self.inodes.resolve(_ino);
 
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
+        // Unlinked but still open files are only reachable through their open file.
+        let orphan_fd = if self.inodes.is_orphan(_ino) {
+            match self.inodes.hot_files.fd(_ino) {
+                Some(fd) => Some(fd),
+                None => {
+                    warn!("Inode {} has neither link nor open file left", _ino);
+                    reply.error(ENOENT);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        // Forced owners and permissions decide what chmod and chown come to.
+        let (_mode, _uid, _gid) = match (self.settings.forced.chmod.filter(_mode), self.settings.forced.chown.filter(_uid), self.settings.forced.chown.filter(_gid)) {
+            (Ok(mode), Ok(uid), Ok(gid)) => (mode, uid, gid),
+            _ => {
+                trace!("Refusing to change the mode or owners of inode {}", _ino);
+                reply.error(EPERM);
+                return;
+            }
+        };
+
+        // Unprivileged mirrors keep the owners and mode asked for aside, rather than on the backing file.
+        let (_mode, _uid, _gid) = if self.settings.override_stat && (_mode.is_some() || _uid.is_some() || _gid.is_some()) {
+            let real_attr = match orphan_fd {
+                Some(fd) => fd_attr(fd),
+                None => path_attr(&path),
+            };
+            let real_attr = match real_attr {
+                Ok(attr) => attr,
+                Err(why) => {
+                    warn!("Could not get attributes for {} : {:?}", path.display(), why);
+                    reply.error(why.raw_os_error().unwrap());
+                    return;
+                }
+            };
+            if real_attr.kind == FileType::RegularFile || real_attr.kind == FileType::Directory {
+                let mut stat = match get_override_stat(&path, orphan_fd) {
+                    Ok(Some(stat)) => stat,
+                    _ => OverrideStat::of(&real_attr),
+                };
+                if let Some(mode) = _mode {
+                    stat.mode = (stat.mode & libc::S_IFMT as u32) | (mode & 0o7777);
+                }
+                let (uid, gid) = match self.map_owner(_uid, _gid) {
+                    Ok(owners) => owners,
+                    Err(e) => {
+                        reply.error(e);
+                        return;
+                    }
+                };
+                stat.uid = uid.unwrap_or(stat.uid);
+                stat.gid = gid.unwrap_or(stat.gid);
+                if let Err(why) = set_override_stat(&path, orphan_fd, &stat) {
+                    warn!("Could not keep {:?} aside for {} : {:?}", stat, path.display(), why);
+                    reply.error(why.raw_os_error().unwrap());
+                    return;
+                }
+                trace!("Kept {:?} aside for {}", stat, path.display());
+            } else {
+                debug!("{} is a {:?}, which cannot keep owners nor mode aside: ignoring the change", path.display(), real_attr.kind);
+            }
+            (None, None, None)
+        } else {
+            (_mode, _uid, _gid)
+        };
+
         if let Some(mode) = _mode {
             trace!("Setting mode {}", mode);
-            let perm = fs::Permissions::from_mode(mode);
-            if let Err(why) = fs::set_permissions(&path, perm) {
-                warn!("Could not set attributes for {} : {:?}", path.display(), why);
-                reply.error(why.raw_os_error().unwrap());
-                return;
+            if let Some(fd) = orphan_fd {
+                if unsafe { libc::fchmod(fd, mode as libc::mode_t) } == -1 {
+                    let e = nix::errno::errno();
+                    warn!("Could not set mode of unlinked inode {} : error {}", _ino, e);
+                    reply.error(e);
+                    return;
+                }
+            } else {
+                let perm = fs::Permissions::from_mode(mode);
+                if let Err(why) = fs::set_permissions(&path, perm) {
+                    warn!("Could not set attributes for {} : {:?}", path.display(), why);
+                    reply.error(why.raw_os_error().unwrap());
+                    return;
+                }
             }
         }
 
         if let Some(size) = _size {
-            match fs::OpenOptions::new().write(true).open(&path){
-                Ok(file) => {
-                    trace!("Setting length to {}", size);
-                    if let Err(why) = file.set_len(size) {
-                        warn!("Could not set length for {} : {:?}", path.display(), why);
+            if let Some(fd) = orphan_fd {
+                trace!("Setting length to {}", size);
+                if unsafe { libc::ftruncate(fd, size as libc::off_t) } == -1 {
+                    let e = nix::errno::errno();
+                    warn!("Could not set length of unlinked inode {} : error {}", _ino, e);
+                    reply.error(e);
+                    return;
+                }
+            } else {
+                match fs::OpenOptions::new().write(true).open(&path){
+                    Ok(file) => {
+                        trace!("Setting length to {}", size);
+                        if let Err(why) = file.set_len(size) {
+                            warn!("Could not set length for {} : {:?}", path.display(), why);
+                            reply.error(why.raw_os_error().unwrap());
+                            return;
+                        }
+                    },
+                    Err(why) => {
+                        warn!("Could not open {} to set its length : {:?}", path.display(), why);
                         reply.error(why.raw_os_error().unwrap());
                         return;
                     }
-                },
-                Err(why) => {
-                    warn!("Could not open {} to set its length : {:?}", path.display(), why);
-                    reply.error(why.raw_os_error().unwrap());
-                    return;
                 }
             }
         }
 
+        // Owners requested by the process, as they are to be written on disk.
+        let (_uid, _gid) = match self.map_owner(_uid, _gid) {
+            Ok(owners) => owners,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
         if _uid.is_some() || _gid.is_some() {
-            let md = match path.symlink_metadata() {
-                Ok(md) => md,
+            let attr = if orphan_fd.is_some() { self.inodes.hot_files.attr(_ino) } else { path_attr(&path) };
+            let attr = match attr {
+                Ok(attr) => attr,
                 Err(why) => {
                     warn!("Could not open {} to set uid/gid : {:?}", path.display(), why);
+                    reply.error(why.raw_os_error().unwrap());
                     return;
                 }
             };
             let uid;
             if _uid.is_none() {
-                uid = md.uid();
+                uid = attr.uid;
             } else {
                 uid = _uid.unwrap();
             }
             let gid;
             if _gid.is_none() {
-                gid = md.gid();
+                gid = attr.gid;
             } else {
                 gid = _gid.unwrap();
             }
             unsafe {
-                if libc::chown(
-                // TODO : with nix path ? (doesn't seem necessary from my experiments...)
-                    path.as_os_str().to_str().unwrap().as_ptr() as *const libc::c_char,
-                    uid as libc::uid_t,
-                    gid as libc::gid_t
-                ) != 0 {
+                if match orphan_fd {
+                    Some(fd) => libc::fchown(fd, uid as libc::uid_t, gid as libc::gid_t),
+                    None => libc::chown(
+                    // TODO : with nix path ? (doesn't seem necessary from my experiments...)
+                        path.as_os_str().to_str().unwrap().as_ptr() as *const libc::c_char,
+                        uid as libc::uid_t,
+                        gid as libc::gid_t
+                    ),
+                } != 0 {
                     let e = nix::errno::errno();
                     warn!("Impossible to change uid and gid of {} : error {}", path.display(), e);
                     reply.error(e);
@@ -790,36 +1126,50 @@ impl Filesystem for MirrorFS {
         }
 
         if _atime.is_some() || _mtime.is_some() {
-            let atime;
-            let mtime;
-            if _atime.is_none() { atime = 0;} else {
-                atime = _atime.unwrap().sec;
-            }
-            if _mtime.is_none() { mtime = 0;} else {
-                mtime = _mtime.unwrap().sec;
-            }
-            match utime::set_file_times(&path, atime as u64, mtime as u64) {
-                Ok(_) => {
-                    trace!("Set atime to {} and mtime to {} for path {}", atime, mtime, path.display());
-                }
-                Err(why) => {
-                    warn!("Could not set atime to {} and mtime to {} for path {} : {:?}", atime, mtime, path.display(), why);
-                    reply.error(why.raw_os_error().unwrap());
+            if let Some(fd) = orphan_fd {
+                let omit = libc::timespec{ tv_sec : 0, tv_nsec : libc::UTIME_OMIT };
+                let times = [
+                    _atime.map_or(omit, utime_spec),
+                    _mtime.map_or(omit, utime_spec),
+                ];
+                if unsafe { libc::futimens(fd, times.as_ptr()) } == -1 {
+                    let e = nix::errno::errno();
+                    warn!("Could not set times of unlinked inode {} : error {}", _ino, e);
+                    reply.error(e);
                     return;
                 }
+            } else {
+                let atime;
+                let mtime;
+                if _atime.is_none() { atime = 0;} else {
+                    atime = utime_secs(_atime.unwrap());
+                }
+                if _mtime.is_none() { mtime = 0;} else {
+                    mtime = utime_secs(_mtime.unwrap());
+                }
+                match utime::set_file_times(&path, atime as u64, mtime as u64) {
+                    Ok(_) => {
+                        trace!("Set atime to {} and mtime to {} for path {}", atime, mtime, path.display());
+                    }
+                    Err(why) => {
+                        warn!("Could not set atime to {} and mtime to {} for path {} : {:?}", atime, mtime, path.display(), why);
+                        reply.error(why.raw_os_error().unwrap());
+                        return;
+                    }
+                }
             }
         }
 
-        if _bkuptime.is_some() || _chgtime.is_some() || _crtime.is_some() || _flags.is_some() {
+        if _bkuptime.is_some() || _chgtime.is_some() || _crtime.is_some() {
             reply.error(ENOSYS);
             return;
         }
 
         // return what is actually on disc.
-        match path.symlink_metadata() {
-            Ok(md) => {
+        match self.stat(&path, orphan_fd) {
+            Ok(attr) => {
                 trace!("Successfully got newly set attributes for {}", path.display());
-                reply.attr(&TTL, &fill_file_attr(&md));
+                reply.attr(&TTL, &self.present(attr));
             }
             Err(why) => {
                 warn!("Could not get attributes for {} : {:?}", path.display(), why);
@@ -828,10 +1178,10 @@ impl Filesystem for MirrorFS {
         }
     }
 
-    fn access (&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+    fn access (&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         let path = self.inodes.resolve(_ino);
 
-        match self.u_access(_req, &path, _mask) {
+        match self.u_access(_req, &path, _mask as u32) {
             Ok(_) => reply.ok(),
             Err(e) => reply.error(e),
         }
@@ -858,12 +1208,14 @@ impl Filesystem for MirrorFS {
         match symlink(&_link, &name) {
             Ok(_) => {
                 trace!("Successfully created symlink {} pointing to {}", name.display(), _link.display());
-                match name.symlink_metadata() {
-                    Ok(md) => {
-                        self.inodes.store(md.ino(), &name);
+                match path_attr(&name) {
+                    Ok(attr) => {
+                        drop(user_token);
+                        let attr = self.own_new(_req, name.parent().unwrap(), libc::AT_FDCWD, &name, attr);
+                        self.inodes.store(attr.ino, &name);
                         reply.entry(
                             &TTL,
-                            &fill_file_attr(&md),
+                            &self.present(attr),
                             0
                         );
                     },
@@ -974,18 +1326,34 @@ impl Filesystem for MirrorFS {
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
+        // Unlinked but still open files are only reachable through their open file.
+        let orphan_fd = if self.inodes.is_orphan(_ino) { self.inodes.hot_files.fd(_ino) } else { None };
+        let getxattr = |value: *mut libc::c_void, len: libc::size_t| -> libc::ssize_t {
+            match orphan_fd {
+                Some(fd) => unsafe {
+                    libc::fgetxattr(
+                        fd,
+                        name.to_str().unwrap().as_ptr() as *const libc::c_char,
+                        value,
+                        len
+                    )
+                },
+                None => path.with_nix_path( |cstr| {
+                    unsafe {
+                        libc::lgetxattr(
+                            cstr.as_ptr(),
+                            name.to_str().unwrap().as_ptr() as *const libc::c_char,
+                            value,
+                            len
+                        )
+                    }
+                }).unwrap(),
+            }
+        };
+
         // loop to avoid race conditions (cf listxattr)
         loop {
-			let res = path.with_nix_path( |cstr| {
-				unsafe {
-					libc::lgetxattr(
-						cstr.as_ptr(),
-						name.to_str().unwrap().as_ptr() as *const libc::c_char,
-						0 as *mut libc::c_void,
-						0
-					)
-                }
-			}).unwrap();
+			let res = getxattr(0 as *mut libc::c_void, 0);
 			match res {
 				len if len >= 0 => {
 					if _size == 0 {
@@ -995,16 +1363,7 @@ impl Filesystem for MirrorFS {
 					let mut value = Vec::with_capacity(len as usize);
 					unsafe {value.set_len(len as usize);}
 					let value = value.as_mut_ptr();
-					let res = path.with_nix_path( |cstr| {
-						unsafe{
-							libc::lgetxattr(
-								cstr.as_ptr(),
-								name.to_str().unwrap().as_ptr() as *const libc::c_char,
-								value as *mut libc::c_void,
-								len as libc::size_t
-							)
-						}
-					}).unwrap();
+					let res = getxattr(value as *mut libc::c_void, len as libc::size_t);
 					if res != len {
 						error!("Extended attribute under name {:?} has changed during the racy operation (for file {}) : retrying now until coherent result !", name, path.display());
 					} else {
@@ -1030,7 +1389,7 @@ impl Filesystem for MirrorFS {
         }
     }
 
-    fn setxattr (&mut self, _req: &Request, _ino: u64, name: &ffi::OsStr, value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+    fn setxattr (&mut self, _req: &Request, _ino: u64, name: &ffi::OsStr, value: &[u8], _flags: i32, _position: u32, reply: ReplyEmpty) {
         let path = self.inodes.resolve(_ino);
 
         // UserMap restores the fsuid/fsgid by Dropping.
@@ -1039,6 +1398,12 @@ impl Filesystem for MirrorFS {
         //What's the use of _position ???
         trace!("_position = {:?}", _position);
 
+        if self.settings.override_stat && name == OVERRIDE_STAT_XATTR {
+            warn!("Refusing to let {:?} be set directly on {}", name, path.display());
+            reply.error(EPERM);
+            return;
+        }
+
 		if path.with_nix_path( |cstr| {
 			unsafe{
 				libc::lsetxattr(
@@ -1065,6 +1430,12 @@ impl Filesystem for MirrorFS {
         // UserMap restores the fsuid/fsgid by Dropping.
         let user_token = self.userprelude(_req);
 
+        if self.settings.override_stat && name == OVERRIDE_STAT_XATTR {
+            warn!("Refusing to let {:?} be removed directly from {}", name, path.display());
+            reply.error(EPERM);
+            return;
+        }
+
         if path.with_nix_path( |cstr| {
 			unsafe{
 				libc::removexattr(
@@ -1111,6 +1482,114 @@ impl Filesystem for MirrorFS {
         }
     }
 
+    // Persists the entries of the directory itself, which is what rename-based atomic writers rely on.
+    fn fsyncdir (&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let path = self.inodes.resolve(ino);
+
+        // UserMap restores the fsuid/fsgid by Dropping.
+        let user_token = self.userprelude(_req);
+
+        let dir = match fs::File::open(&path) {
+            Ok(dir) => dir,
+            Err(why) => {
+                warn!("Could not open directory {} to fsync it : {:?}", path.display(), why);
+                reply.error(why.raw_os_error().unwrap());
+                return;
+            }
+        };
+        let res = if _datasync {
+            trace!("Syncing data (not metadata) of directory {}", path.display());
+            dir.sync_data()
+        } else {
+            trace!("Syncing data and metadata of directory {}", path.display());
+            dir.sync_all()
+        };
+        match res {
+            Ok(_) => reply.ok(),
+            Err(why) => {
+                warn!("Could not fsync directory {} : {:?}", path.display(), why);
+                reply.error(why.raw_os_error().unwrap());
+            }
+        }
+    }
+
+    fn getlk (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: i32, _pid: u32, reply: ReplyLock) {
+        debug!("getlk callback for ino {} and lock owner {}, from {} to {} with type {}", _ino, _lock_owner, _start, _end, _typ);
+
+        // UserMap restores the fsuid/fsgid by Dropping.
+        let user_token = self.userprelude(_req);
+
+        let fd = match self.inodes.hot_files.lock_fd(_fh, _ino, _lock_owner) {
+            Ok(fd) => fd,
+            Err(why) => {
+                warn!("Could not get a file description for lock owner {} on {} : {:?}", _lock_owner, self.inodes.resolve(_ino).display(), why);
+                reply.error(why.raw_os_error().unwrap());
+                return;
+            }
+        };
+        let mut lock = fuse_to_flock(_typ, _start, _end);
+        if unsafe { libc::fcntl(fd, libc::F_OFD_GETLK, &mut lock as *mut libc::flock) } == -1 {
+            let e = nix::errno::errno();
+            warn!("Could not test lock on {} : error {}", self.inodes.resolve(_ino).display(), e);
+            reply.error(e);
+            return;
+        }
+        let (start, end, typ, pid) = flock_to_fuse(&lock);
+        trace!("Lock test on {} yields type {} from {} to {} (pid {})", self.inodes.resolve(_ino).display(), typ, start, end, pid);
+        reply.locked(start, end, typ, pid);
+    }
+
+    fn setlk (&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: i32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
+        debug!("setlk callback for ino {} and lock owner {}, from {} to {} with type {} (sleep = {})", _ino, _lock_owner, _start, _end, _typ, _sleep);
+
+        // UserMap restores the fsuid/fsgid by Dropping.
+        let user_token = self.userprelude(_req);
+
+        let fd = match self.inodes.hot_files.lock_fd(_fh, _ino, _lock_owner) {
+            Ok(fd) => fd,
+            Err(why) => {
+                warn!("Could not get a file description for lock owner {} on {} : {:?}", _lock_owner, self.inodes.resolve(_ino).display(), why);
+                reply.error(why.raw_os_error().unwrap());
+                return;
+            }
+        };
+        let lock = fuse_to_flock(_typ, _start, _end);
+        if unsafe { libc::fcntl(fd, libc::F_OFD_SETLK, &lock as *const libc::flock) } == -1 {
+            let e = nix::errno::errno();
+            if _sleep && (e == libc::EAGAIN || e == libc::EACCES) {
+                // Requests are served one at a time: the wait goes to a thread of its own, so that the holder can still unlock through the mirror meanwhile.
+                // It waits on a duplicate of the lock owner's file description, which is where the lock lands.
+                let waiter = unsafe { libc::dup(fd) };
+                if waiter == -1 {
+                    let e = nix::errno::errno();
+                    warn!("Could not wait for the lock on {} : error {}", self.inodes.resolve(_ino).display(), e);
+                    reply.error(e);
+                    return;
+                }
+                let path = self.inodes.resolve(_ino);
+                debug!("Lock on {} is held elsewhere, lock owner {} waits for it", path.display(), _lock_owner);
+                thread::spawn(move || {
+                    let res = unsafe { libc::fcntl(waiter, libc::F_OFD_SETLKW, &lock as *const libc::flock) };
+                    let e = nix::errno::errno();
+                    unsafe { libc::close(waiter); }
+                    if res == -1 {
+                        trace!("Could not set lock on {} : error {}", path.display(), e);
+                        reply.error(e);
+                    } else {
+                        trace!("Set lock of type {} from {} to {} on {} after waiting", lock.l_type, _start, _end, path.display());
+                        reply.ok();
+                    }
+                });
+            } else {
+                trace!("Could not set lock on {} : error {}", self.inodes.resolve(_ino).display(), e);
+                reply.error(e);
+            }
+            return;
+        }
+        trace!("Set lock of type {} from {} to {} on {} for lock owner {}", _typ, _start, _end, self.inodes.resolve(_ino).display(), _lock_owner);
+        reply.ok();
+    }
+
     fn statfs (&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         let path = self.inodes.resolve(_ino);
 
@@ -1138,4 +1617,162 @@ impl Filesystem for MirrorFS {
             );
         }
     }
+
+    fn copy_file_range (&mut self, _req: &Request, ino_in: u64, fh_in: u64, offset_in: i64, ino_out: u64, fh_out: u64, offset_out: i64, len: u64, _flags: u32, reply: ReplyWrite) {
+        debug!("copy_file_range callback from ino {} (handle {}) at offset {} to ino {} (handle {}) at offset {} for the size of {}", ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len);
+
+        // UserMap restores the fsuid/fsgid by Dropping.
+        let user_token = self.userprelude(_req);
+
+        // The reply can only carry a 32 bits size: the kernel calls back for the rest.
+        let len = cmp::min(len, u32::max_value() as u64);
+        let src = self.inodes.hot_files.take_file(fh_in);
+        let res = if fh_in == fh_out {
+            copy_range(&src, offset_in as u64, &src, offset_out as u64, len)
+        } else {
+            let dst = self.inodes.hot_files.take_file(fh_out);
+            let res = copy_range(&src, offset_in as u64, &dst, offset_out as u64, len);
+            self.inodes.hot_files.restore_file(fh_out, dst, ino_out);
+            res
+        };
+        self.inodes.hot_files.restore_file(fh_in, src, ino_in);
+        match res {
+            Ok(n) => {
+                trace!("Successfully copied {} bytes from {} to {}", n, self.inodes.resolve(ino_in).display(), self.inodes.resolve(ino_out).display());
+                reply.written(n as u32);
+            },
+            Err(why) => {
+                warn!("Could not copy {} bytes from {} to {} : {:?}", len, self.inodes.resolve(ino_in).display(), self.inodes.resolve(ino_out).display(), why);
+                reply.error(why.raw_os_error().unwrap());
+            }
+        }
+    }
+
+    fn readdirplus (&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        trace!("fn readdirplus for ino {}, at offset {}", ino, offset);
+
+        // UserMap restores the fsuid/fsgid by Dropping.
+        let user_token = self.userprelude(_req);
+
+        let dir = self.inodes.resolve(ino);
+        let mut stream = match self.inodes.hot_files.take_dir(_fh) {
+            Some(stream) => stream,
+            None => {
+                warn!("Handle {} to directory {} has no directory stream", _fh, ino);
+                reply.error(EBADF);
+                return;
+            }
+        };
+        stream.seek(offset as u64);
+        let mut count = 0;
+        loop {
+            let dentry = match stream.next_entry() {
+                Some(Ok(dentry)) => dentry,
+                Some(Err(why)) => {
+                    warn!("Could not read an entry of directory {} : {:?}", dir.display(), why);
+                    if count == 0 {
+                        self.inodes.hot_files.restore_dir(_fh, stream);
+                        reply.error(why.raw_os_error().unwrap());
+                        return;
+                    }
+                    break;
+                },
+                None => break,
+            };
+            // Attributes come from the same scan, relative to the directory: no lookup round-trip needed.
+            let name = match ffi::CString::new(dentry.name.as_bytes()) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let (mut attr, ttl, known) = match at_attr(stream.fd(), &name, 0) {
+                Ok(attr) => (attr, TTL, true),
+                Err(ref why) if why.raw_os_error() == Some(ENOENT) => {
+                    trace!("{:?} vanished from {} while listing it : {:?}", dentry.name, dir.display(), why);
+                    continue;
+                },
+                Err(why) => {
+                    // In a directory one may read but not search, for instance: the entry is still listed, and its attributes expire at once so that lookup and getattr give the actual error.
+                    debug!("Could not get the attributes of {:?} in {} : {:?}", dentry.name, dir.display(), why);
+                    (unknown_attr(dentry.ino, dentry.kind), Duration::from_secs(0), false)
+                }
+            };
+            if dentry.name == "." {
+                attr.ino = ino;
+            } else if dentry.name == ".." {
+                attr.ino = self.parent_ino(ino);
+            } else {
+                let path = dir.join(&dentry.name);
+                if known {
+                    self.apply_override_stat(&mut attr, &path, None);
+                }
+                self.inodes.store(attr.ino, &path);
+            }
+            trace!("adding {:?} to reply with ino {} and offset {}", dentry.name, attr.ino, dentry.offset);
+            if reply.add(attr.ino, dentry.offset as i64, &dentry.name, &ttl, &self.present(attr), 0) {
+                trace!("DirEntry buffer filled! Breaking : waiting for kernel to call back and take the rest of the dentries...");
+                break;
+            }
+            count += 1;
+        }
+        self.inodes.hot_files.restore_dir(_fh, stream);
+        debug!("Filled DirEntryPlus buffer. Now Sending to the kernel.");
+        reply.ok();
+    }
+
+    // Only the inode flags ioctls of lsattr and chattr are forwarded.
+    fn ioctl (&mut self, _req: &Request, ino: u64, _fh: u64, _flags: u32, cmd: u32, in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        debug!("ioctl callback for ino {} with command {:x}", ino, cmd);
+        let path = self.inodes.resolve(ino);
+
+        // UserMap restores the fsuid/fsgid by Dropping.
+        let user_token = self.userprelude(_req);
+
+        let orphan_fd = if self.inodes.is_orphan(ino) { self.inodes.hot_files.fd(ino) } else { None };
+        match cmd as libc::c_ulong {
+            FS_IOC_GETFLAGS => {
+                let flags = match orphan_fd {
+                    Some(fd) => get_inode_flags(fd),
+                    None => get_path_flags(&path),
+                };
+                match flags {
+                    Ok(flags) => {
+                        trace!("Inode flags of {} are {:x}", path.display(), flags);
+                        reply.ioctl(0, &flags.to_ne_bytes());
+                    },
+                    Err(why) => {
+                        warn!("Could not get inode flags of {} : {:?}", path.display(), why);
+                        reply.error(why.raw_os_error().unwrap());
+                    }
+                }
+            },
+            FS_IOC_FSGETXATTR => {
+                let fsxattr = match orphan_fd {
+                    Some(fd) => get_inode_fsxattr(fd),
+                    None => get_path_fsxattr(&path),
+                };
+                match fsxattr {
+                    Ok(fsxattr) => reply.ioctl(0, &fsxattr),
+                    Err(why) => {
+                        warn!("Could not get the fsxattr of {} : {:?}", path.display(), why);
+                        reply.error(why.raw_os_error().unwrap());
+                    }
+                }
+            },
+            FS_IOC_SETFLAGS => {
+                if in_data.len() < 4 {
+                    reply.error(libc::EINVAL);
+                    return;
+                }
+                let flags = u32::from_ne_bytes([in_data[0], in_data[1], in_data[2], in_data[3]]);
+                match self.change_inode_flags(_req, &path, orphan_fd, flags) {
+                    Ok(_) => reply.ioctl(0, &[]),
+                    Err(e) => reply.error(e),
+                }
+            },
+            _ => {
+                trace!("Unsupported ioctl {:x} on {}", cmd, path.display());
+                reply.error(ENOTTY);
+            }
+        }
+    }
 }
@@ -0,0 +1,210 @@
+use libc::EPERM;
+use user::{Uid, Gid};
+
+/// What chmod and chown do on a mirror which forces owners or permissions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttrPolicy {
+	Passthrough, // Changed on the source, whatever is shown.
+	Ignore, // Silently dropped.
+	Deny, // Refused with EPERM.
+}
+
+impl AttrPolicy {
+	pub fn parse(policy: &str) -> Option<AttrPolicy> {
+		match policy {
+			"passthrough" => Some(AttrPolicy::Passthrough),
+			"ignore" => Some(AttrPolicy::Ignore),
+			"deny" => Some(AttrPolicy::Deny),
+			_ => None,
+		}
+	}
+
+	/// The attribute a setattr request is to change, if any.
+	pub fn filter(self, value: Option<u32>) -> Result<Option<u32>, i32> {
+		match (self, value) {
+			(AttrPolicy::Passthrough, value) => Ok(value),
+			(AttrPolicy::Deny, Some(_)) => Err(EPERM),
+			(_, _) => Ok(None),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+	Add,
+	Remove,
+	Set,
+}
+
+/// One rule of a bindfs-like permission list, such as u=rwD or 0644.
+#[derive(Clone, Copy, Debug)]
+pub enum PermRule {
+	Octal(u16),
+	Symbolic {
+		who : u16, // The rwx bits of the classes the rule applies to.
+		dirs : bool,
+		files : bool,
+		op : Op,
+		letters : u8, // What 'r', 'w', 'x', 'X', 'D', 's', 't' were given.
+	},
+}
+
+const R : u8 = 1;
+const W : u8 = 2;
+const X : u8 = 4;
+const BIG_X : u8 = 8; // Executable if a directory, or executable by someone already.
+const D : u8 = 16; // Executable if a directory.
+const S : u8 = 32;
+const T : u8 = 64;
+
+impl PermRule {
+	/// Parses a comma or colon separated list of rules, e.g. "u=rwD,g=rD,o=". Directory or file only rules are prefixed with d or f: "fa-x".
+	pub fn parse_list(list: &str) -> Result<Vec<PermRule>, String> {
+		list.split(|c| c == ',' || c == ':').filter(|rule| !rule.is_empty()).map(PermRule::parse).collect()
+	}
+
+	fn parse(rule: &str) -> Result<PermRule, String> {
+		if rule.chars().all(|c| c >= '0' && c <= '7') {
+			return match u16::from_str_radix(rule, 8) {
+				Ok(perm) if perm <= 0o7777 => Ok(PermRule::Octal(perm)),
+				_ => Err(format!("{} is not a valid octal mode", rule)),
+			};
+		}
+		let mut who = 0;
+		let mut dirs = true;
+		let mut files = true;
+		let mut chars = rule.chars();
+		let op = loop {
+			match chars.next() {
+				Some('u') => who |= 0o700,
+				Some('g') => who |= 0o070,
+				Some('o') => who |= 0o007,
+				Some('a') => who |= 0o777,
+				Some('d') => files = false,
+				Some('f') => dirs = false,
+				Some('+') => break Op::Add,
+				Some('-') => break Op::Remove,
+				Some('=') => break Op::Set,
+				_ => return Err(format!("{} lacks an operator among +, - and =", rule)),
+			}
+		};
+		if !dirs && !files {
+			return Err(format!("{} applies to neither directories nor files", rule));
+		}
+		let mut letters = 0;
+		for c in chars {
+			letters |= match c {
+				'r' => R,
+				'w' => W,
+				'x' => X,
+				'X' => BIG_X,
+				'D' => D,
+				's' => S,
+				't' => T,
+				_ => return Err(format!("{} has an unknown permission {}", rule, c)),
+			};
+		}
+		Ok(PermRule::Symbolic {
+			who : if who == 0 { 0o777 } else { who },
+			dirs : dirs,
+			files : files,
+			op : op,
+			letters : letters,
+		})
+	}
+
+	pub fn apply(&self, perm: u16, is_dir: bool) -> u16 {
+		match *self {
+			PermRule::Octal(mode) => mode,
+			PermRule::Symbolic { who, dirs, files, op, letters } => {
+				if (is_dir && !dirs) || (!is_dir && !files) {
+					return perm;
+				}
+				let mut rwx = 0;
+				if letters & R != 0 { rwx |= 0o444; }
+				if letters & W != 0 { rwx |= 0o222; }
+				if letters & X != 0
+					|| (letters & BIG_X != 0 && (is_dir || perm & 0o111 != 0))
+					|| (letters & D != 0 && is_dir) { rwx |= 0o111; }
+				let mut bits = rwx & who;
+				// The special bits of the classes concerned.
+				let mut special = 0;
+				if who & 0o700 != 0 { special |= 0o4000; }
+				if who & 0o070 != 0 { special |= 0o2000; }
+				if letters & S != 0 { bits |= special; }
+				if letters & T != 0 && who & 0o007 != 0 { bits |= 0o1000; }
+				match op {
+					Op::Add => perm | bits,
+					Op::Remove => perm & !bits,
+					Op::Set => (perm & !(who | special | if who & 0o007 != 0 { 0o1000 } else { 0 })) | bits,
+				}
+			},
+		}
+	}
+}
+
+/// Owners and permissions every file is shown with, and what chmod and chown then do.
+pub struct ForcedAttrs {
+	pub uid : Option<Uid>,
+	pub gid : Option<Gid>,
+	pub perms : Vec<PermRule>,
+	pub chmod : AttrPolicy,
+	pub chown : AttrPolicy,
+}
+
+impl ForcedAttrs {
+	pub fn perm(&self, perm: u16, is_dir: bool) -> u16 {
+		self.perms.iter().fold(perm, |perm, rule| rule.apply(perm, is_dir))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::PermRule;
+
+	fn perm(rules: &str, perm: u16, is_dir: bool) -> u16 {
+		PermRule::parse_list(rules).unwrap().iter().fold(perm, |perm, rule| rule.apply(perm, is_dir))
+	}
+
+	#[test]
+	fn symbolic_rules() {
+		assert_eq!(perm("u=rwD,g=rD,o=", 0o644, false), 0o640);
+		assert_eq!(perm("u=rwD,g=rD,o=", 0o755, false), 0o640);
+		assert_eq!(perm("u=rwD,g=rD,o=", 0o700, true), 0o750);
+		assert_eq!(perm("u=rwD,g=rD,o=", 0o777, true), 0o750);
+		assert_eq!(perm("u=rwD:g=rD:o=", 0o777, true), 0o750);
+	}
+
+	#[test]
+	fn octal_rules() {
+		assert_eq!(perm("0644", 0o777, false), 0o644);
+		assert_eq!(perm("755", 0o600, true), 0o755);
+		assert_eq!(perm("0644,u+x", 0o777, false), 0o744);
+		assert_eq!(perm("7777", 0, false), 0o7777);
+	}
+
+	#[test]
+	fn file_only_rules() {
+		assert_eq!(perm("fa-x", 0o755, false), 0o644);
+		assert_eq!(perm("fa-x", 0o755, true), 0o755);
+		assert_eq!(perm("da-x", 0o755, false), 0o755);
+		assert_eq!(perm("da-x", 0o755, true), 0o644);
+	}
+
+	#[test]
+	fn big_x() {
+		assert_eq!(perm("a+X", 0o644, false), 0o644);
+		assert_eq!(perm("a+X", 0o744, false), 0o755);
+		assert_eq!(perm("a+X", 0o640, true), 0o751);
+		assert_eq!(perm("go=rX", 0o700, false), 0o755);
+		assert_eq!(perm("go=rX", 0o604, false), 0o644);
+		assert_eq!(perm("go=rX", 0o640, false), 0o644);
+	}
+
+	#[test]
+	fn rejected_rules() {
+		for rules in &["u", "urw", "u+q", "df+r", "8", "77777", "u+r,g", "u=rw,z+r"] {
+			assert!(PermRule::parse_list(rules).is_err(), "{} should be rejected", rules);
+		}
+	}
+}
@@ -1,18 +1,88 @@
-use fuse::Request;
+use fuser::{FileAttr, FileType, Request};
 use mirrorfs::MirrorFS;
-use capabilities::{Capabilities, Capability};
+use capabilities::{Capabilities, Capability, Flag};
 #[cfg(feature="enable_unsecure_features")]
-use capabilities::Flag;
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use libc;
+use fasthashes::*;
 
 pub type Uid = u32;
 pub type Gid = u32;
 
+const GROUPS_TTL : u64 = 5; // Seconds during which the supplementary groups of a user are trusted without asking /proc again.
+
+/// Supplementary groups of the requesting users, which the kernel does not pass along with requests.
+pub struct GroupsCache {
+	mutex : Mutex<FastMap<Uid, (Instant, Vec<Gid>)>>,
+}
+
+impl GroupsCache {
+	pub fn new() -> GroupsCache {
+		GroupsCache {
+			mutex : Mutex::new(FastMap::with_capacity(10)),
+		}
+	}
+	pub fn get(&self, uid: Uid, pid: u32) -> Vec<Gid> {
+		let mut cache = self.mutex.lock().expect("This is not supposed to happen...");
+		if let Some(&(ref since, ref groups)) = cache.get(&uid) {
+			if since.elapsed() < Duration::from_secs(GROUPS_TTL) {
+				return groups.clone();
+			}
+		}
+		match read_groups(pid) {
+			Some(groups) => {
+				trace!("Supplementary groups of uid {} are {:?}", uid, groups);
+				cache.insert(uid, (Instant::now(), groups.clone()));
+				groups
+			},
+			None => {
+				// The process may be gone already: no supplementary group then, and nothing cached.
+				debug!("Could not get the supplementary groups of process {} (uid {})", pid, uid);
+				Vec::new()
+			}
+		}
+	}
+}
+
+fn read_groups(pid: u32) -> Option<Vec<Gid>> {
+	let mut status = String::new();
+	if let Err(_) = File::open(format!("/proc/{}/status", pid)).and_then(|mut file| file.read_to_string(&mut status)) {
+		return None;
+	}
+	for line in status.lines() {
+		if line.starts_with("Groups:") {
+			return Some(line["Groups:".len()..].split_whitespace().filter_map(|gid| gid.parse().ok()).collect());
+		}
+	}
+	None
+}
+
+fn current_groups() -> io::Result<Vec<Gid>> {
+	let len = unsafe { libc::getgroups(0, 0 as *mut libc::gid_t) };
+	if len < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let mut groups : Vec<Gid> = vec![0; len as usize];
+	let len = unsafe { libc::getgroups(len, groups.as_mut_ptr()) };
+	if len < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	groups.truncate(len as usize);
+	Ok(groups)
+}
+
 #[cfg(feature="enable_unsecure_features")]
 pub struct UserMap {
 	o_user : Uid,
 	o_group : Gid,
 	n_user : Uid,
 	n_group : Gid,
+	o_groups : Option<Vec<Gid>>,
 	#[allow(dead_code)]
 	caps : Option<CapToken>,
 }
@@ -22,6 +92,7 @@ pub struct UserMap {
 	o_group : Gid,
 	n_user : Uid,
 	n_group : Gid,
+	o_groups : Option<Vec<Gid>>,
 }
 
 impl Drop for UserMap {
@@ -38,15 +109,101 @@ impl Drop for UserMap {
 				syscall!(SETFSGID, self.o_group);
 			}
 		}
+		if let Some(ref groups) = self.o_groups {
+			trace!("Restoring our own supplementary groups {:?}", groups);
+			unsafe {
+				syscall!(SETGROUPS, groups.len(), groups.as_ptr());
+			}
+		}
 	}
 }
 
+/// NFS-like squashing of the requesting users onto the anonymous user and group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Squash {
+	Off,
+	Root, // uid and gid 0 only, as root_squash.
+	All, // every user and group, as all_squash.
+}
+
+/// Who owns the files the requesting users create.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CreateOwner {
+	Caller, // The requesting user, as embodied.
+	Mounter, // The user running the filesystem.
+	Parent, // The owners of the parent directory.
+}
+
+impl CreateOwner {
+	pub fn parse(policy: &str) -> Option<CreateOwner> {
+		match policy {
+			"caller" => Some(CreateOwner::Caller),
+			"mounter" => Some(CreateOwner::Mounter),
+			"parent" => Some(CreateOwner::Parent),
+			_ => None,
+		}
+	}
+}
+
+/// A range of COUNT ids shifted from INSIDE (as processes see them) to OUTSIDE (as they are on disk), like a line of /proc/<pid>/uid_map.
+#[cfg(feature="enable_unsecure_features")]
+#[derive(Clone, Copy, Debug)]
+pub struct IdRange {
+	pub inside : u32,
+	pub outside : u32,
+	pub count : u32,
+}
+
+#[cfg(feature="enable_unsecure_features")]
+impl IdRange {
+	/// Parses INSIDE:OUTSIDE:COUNT.
+	pub fn parse(range: &str) -> Option<IdRange> {
+		let ids : Vec<Option<u32>> = range.split(':').map(|id| id.parse().ok()).collect();
+		match ids.as_slice() {
+			&[Some(inside), Some(outside), Some(count)] if count > 0
+				&& inside.checked_add(count - 1).is_some()
+				&& outside.checked_add(count - 1).is_some() => Some(IdRange {
+				inside : inside,
+				outside : outside,
+				count : count,
+			}),
+			_ => None,
+		}
+	}
+}
+
+/// Disk to process mapping. When several ids are mapped onto the same one, the smallest one gets it back.
+#[cfg(feature="enable_unsecure_features")]
+pub fn reverse_map(map: &FastMap<u32, u32>, what: &str) -> FastMap<u32, u32> {
+	let mut reverse : FastMap<u32, u32> = FastMap::with_capacity(map.len());
+	for (origin, embodied) in map.iter() {
+		let shown = reverse.entry(*embodied).or_insert(*origin);
+		if *shown != *origin {
+			let smallest = cmp::min(*shown, *origin);
+			warn!("Both {} {} and {} are mapped onto {}: its files will be shown as belonging to {}.", what, *shown, origin, embodied, smallest);
+			*shown = smallest;
+		}
+	}
+	reverse
+}
+
+#[cfg(feature="enable_unsecure_features")]
+pub const OVERFLOW_ID : u32 = 65534; // Who owns what no range maps, as for the kernel.
+
+#[cfg(feature="enable_unsecure_features")]
+pub fn shift_out(ranges: &[IdRange], id: u32) -> Option<u32> {
+	ranges.iter().find(|r| id >= r.inside && id - r.inside < r.count).map(|r| r.outside + (id - r.inside))
+}
+
 #[cfg(feature="enable_unsecure_features")]
+pub fn shift_in(ranges: &[IdRange], id: u32) -> Option<u32> {
+	ranges.iter().find(|r| id >= r.outside && id - r.outside < r.count).map(|r| r.inside + (id - r.outside))
+}
+
 pub struct CapToken {
 	caps : Vec<Capability>
 }
 
-#[cfg(feature="enable_unsecure_features")]
 impl Drop for CapToken {
 	fn drop(&mut self) {
 		let mut caps = Capabilities::from_current_proc().unwrap();
@@ -91,6 +248,30 @@ impl MirrorFS {
 			trace!("No need to embody requesting group.");
 			o_group = group;
 		}
+		// The supplementary groups of the requesting process, for files it may only access through one of them.
+		let o_groups = if self.settings.has_cap(Capability::CAP_SETGID) {
+			let groups = self.request_groups(req);
+			match current_groups() {
+				Ok(own_groups) => {
+					if groups != own_groups {
+						trace!("Switching supplementary groups to {:?}", groups);
+						if unsafe { syscall!(SETGROUPS, groups.len(), groups.as_ptr()) } as isize != 0 {
+							warn!("Could not switch supplementary groups to {:?}", groups);
+						}
+						Some(own_groups)
+					} else {
+						None
+					}
+				},
+				Err(why) => {
+					warn!("Could not get our own supplementary groups, so we keep them : {:?}", why);
+					None
+				}
+			}
+		} else {
+			trace!("Cannot set supplementary groups: you need CAP_SETGID on the fuse implementation");
+			None
+		};
 		
 		#[cfg(feature="enable_unsecure_features")] {
 			// TODO: optimize for regular case where no full access.
@@ -107,6 +288,7 @@ impl MirrorFS {
 				o_group : o_group,
 				n_user : user,
 				n_group : group,
+				o_groups : o_groups,
 				caps : cap_token,
 			}
 		}
@@ -117,34 +299,155 @@ impl MirrorFS {
 				o_group : o_group,
 				n_user : user,
 				n_group : group,
+				o_groups : o_groups,
 			}
 		}
     }
 
 	#[cfg(feature="enable_unsecure_features")]
     pub fn usermap(&self, req: &Request) -> (Uid, Gid) {
-        let mut calling_u = req.uid();
-        let mut calling_g = req.gid();
-		if self.settings.user_map.is_empty() {
+        let mut calling_u = self.squash_uid(req.uid());
+        let mut calling_g = self.squash_gid(req.gid());
+		if self.settings.user_map.is_empty() && self.settings.group_map.is_empty() && self.settings.uid_ranges.is_empty() && self.settings.gid_ranges.is_empty() {
 			trace!("No user mapping on requests.");
 		} else {
 			if let Some(mapped_u) = self.settings.user_map.get(&calling_u) {
 				trace!("Mapping uid {} to {}.", calling_u, mapped_u);
 				calling_u = *mapped_u;
+			} else if !self.settings.uid_ranges.is_empty() {
+				// Requesters out of every range act as the overflow id, so that they cannot reach the source under their own ids.
+				let shifted_u = shift_out(&self.settings.uid_ranges, calling_u).unwrap_or(OVERFLOW_ID);
+				trace!("Shifting uid {} to {}.", calling_u, shifted_u);
+				calling_u = shifted_u;
 			}
 			if let Some(mapped_g) = self.settings.group_map.get(&calling_g) {
 				trace!("Mapping gid {} to {}", calling_g, mapped_g);
 				calling_g = *mapped_g;
+			} else if !self.settings.gid_ranges.is_empty() {
+				let shifted_g = shift_out(&self.settings.gid_ranges, calling_g).unwrap_or(OVERFLOW_ID);
+				trace!("Shifting gid {} to {}.", calling_g, shifted_g);
+				calling_g = shifted_g;
 			}
 		}
 		(calling_u, calling_g)
     }
     #[cfg(not(feature="enable_unsecure_features"))]
     pub fn usermap(&self, req: &Request) -> (Uid, Gid) {
-		(req.uid(), req.gid())
+		(self.squash_uid(req.uid()), self.squash_gid(req.gid()))
     }
+
+	pub fn squash_uid(&self, uid: Uid) -> Uid {
+		match self.settings.squash {
+			Squash::All => self.settings.anon_uid,
+			Squash::Root if uid == 0 => {
+				trace!("Squashing root to uid {}.", self.settings.anon_uid);
+				self.settings.anon_uid
+			},
+			_ => uid,
+		}
+	}
+	pub fn squash_gid(&self, gid: Gid) -> Gid {
+		match self.settings.squash {
+			Squash::All => self.settings.anon_gid,
+			Squash::Root if gid == 0 => {
+				trace!("Squashing group root to gid {}.", self.settings.anon_gid);
+				self.settings.anon_gid
+			},
+			_ => gid,
+		}
+	}
+
+	/// Supplementary groups of the requesting process, as they act on disk. Like NFS, all_squash drops them all.
+	pub fn request_groups(&self, req: &Request) -> Vec<Gid> {
+		let groups : Vec<Gid> = match self.settings.squash {
+			Squash::All => Vec::new(),
+			_ => self.groups.get(req.uid(), req.pid()).iter().map(|gid| self.squash_gid(*gid)).collect(),
+		};
+		// Mapped and shifted like the primary group, but groups out of every range are dropped rather than acting as the overflow id.
+		#[cfg(feature="enable_unsecure_features")]
+		let groups : Vec<Gid> = groups.iter().filter_map(|gid| match self.settings.group_map.get(gid) {
+			Some(mapped_g) => Some(*mapped_g),
+			None if self.settings.gid_ranges.is_empty() => Some(*gid),
+			None => shift_out(&self.settings.gid_ranges, *gid),
+		}).collect();
+		groups
+	}
+
+	/// Owners given by a process (chown), as they are to be written on disk.
+	pub fn map_owner(&self, mut uid: Option<Uid>, mut gid: Option<Gid>) -> Result<(Option<Uid>, Option<Gid>), i32> {
+		uid = uid.map(|u| self.squash_uid(u));
+		gid = gid.map(|g| self.squash_gid(g));
+		#[cfg(feature="enable_unsecure_features")] {
+			// As with idmapped mounts, files cannot be given away to ids out of every range.
+			if let Some(u) = uid {
+				uid = match self.settings.user_map.get(&u) {
+					Some(mapped_u) => Some(*mapped_u),
+					None if self.settings.uid_ranges.is_empty() => Some(u),
+					None => match shift_out(&self.settings.uid_ranges, u) {
+						Some(shifted_u) => Some(shifted_u),
+						None => {
+							debug!("uid {} is out of every range: refusing to give files to it.", u);
+							return Err(libc::EOVERFLOW);
+						}
+					},
+				};
+			}
+			if let Some(g) = gid {
+				gid = match self.settings.group_map.get(&g) {
+					Some(mapped_g) => Some(*mapped_g),
+					None if self.settings.gid_ranges.is_empty() => Some(g),
+					None => match shift_out(&self.settings.gid_ranges, g) {
+						Some(shifted_g) => Some(shifted_g),
+						None => {
+							debug!("gid {} is out of every range: refusing to give files to it.", g);
+							return Err(libc::EOVERFLOW);
+						}
+					},
+				};
+			}
+		}
+		Ok((uid, gid))
+	}
+
+	/// Attributes on disk, as the requesting processes are to see them.
+	pub fn present(&self, mut attr: FileAttr) -> FileAttr {
+		#[cfg(feature="enable_unsecure_features")] {
+			// When Alice is mapped onto Tom, Tom's files are hers. As with idmapped mounts, owners out of every range show as the overflow ids.
+			if let Some(unmapped_u) = self.settings.user_unmap.get(&attr.uid) {
+				attr.uid = *unmapped_u;
+			} else if !self.settings.uid_ranges.is_empty() {
+				attr.uid = shift_in(&self.settings.uid_ranges, attr.uid).unwrap_or(OVERFLOW_ID);
+			}
+			if let Some(unmapped_g) = self.settings.group_unmap.get(&attr.gid) {
+				attr.gid = *unmapped_g;
+			} else if !self.settings.gid_ranges.is_empty() {
+				attr.gid = shift_in(&self.settings.gid_ranges, attr.gid).unwrap_or(OVERFLOW_ID);
+			}
+		}
+		let forced = &self.settings.forced;
+		if let Some(uid) = forced.uid {
+			attr.uid = uid;
+		}
+		if let Some(gid) = forced.gid {
+			attr.gid = gid;
+		}
+		if !forced.perms.is_empty() {
+			attr.perm = forced.perm(attr.perm, attr.kind == FileType::Directory);
+		}
+		attr
+	}
     
-    #[cfg(feature="enable_unsecure_features")]
+    /// Whether the requesting user may hold the capabilities of the fuse implementation for a protected change: root, or a user given full access.
+    pub fn may_protect(&self, req: &Request) -> bool {
+		let (uid, _) = self.usermap(req);
+		#[cfg(feature="enable_unsecure_features")] {
+			if self.settings.fullaccess.contains(&uid) {
+				return true;
+			}
+		}
+		uid == 0
+	}
+
     pub fn set_cap(&self, caps : &[Capability]) -> CapToken {
 		let mut added_caps : Vec<Capability> = Vec::new();
 		for cap in caps.iter() {
@@ -161,3 +464,52 @@ impl MirrorFS {
 		CapToken{caps: added_caps}
 	}
 }
+
+#[cfg(all(test, feature="enable_unsecure_features"))]
+mod tests {
+	use super::{IdRange, shift_in, shift_out};
+
+	#[test]
+	fn id_ranges_parse() {
+		let r = IdRange::parse("0:100000:65536").unwrap();
+		assert_eq!((r.inside, r.outside, r.count), (0, 100000, 65536));
+		assert!(IdRange::parse("4294967295:0:1").is_some());
+		assert!(IdRange::parse("0:4294967295:1").is_some());
+	}
+
+	#[test]
+	fn id_ranges_reject() {
+		for range in &["", "0:100000", "0:100000:65536:1", "a:100000:65536", "0:100000:-1", "0:100000:0", "4294967295:0:2", "0:4294967295:2", "0:100000:4294967296"] {
+			assert!(IdRange::parse(range).is_none(), "{} should be rejected", range);
+		}
+	}
+
+	#[test]
+	fn id_ranges_shift() {
+		let ranges = [IdRange::parse("0:100000:1000").unwrap(), IdRange::parse("5000:200000:10").unwrap()];
+		assert_eq!(shift_out(&ranges, 0), Some(100000));
+		assert_eq!(shift_out(&ranges, 999), Some(100999));
+		assert_eq!(shift_out(&ranges, 1000), None);
+		assert_eq!(shift_out(&ranges, 5009), Some(200009));
+		assert_eq!(shift_out(&ranges, 5010), None);
+		assert_eq!(shift_in(&ranges, 100000), Some(0));
+		assert_eq!(shift_in(&ranges, 100999), Some(999));
+		assert_eq!(shift_in(&ranges, 99999), None);
+		assert_eq!(shift_in(&ranges, 101000), None);
+		assert_eq!(shift_in(&ranges, 200009), Some(5009));
+		for id in &[0, 999, 5000, 5009] {
+			assert_eq!(shift_in(&ranges, shift_out(&ranges, *id).unwrap()), Some(*id));
+		}
+	}
+
+	#[test]
+	fn id_ranges_at_the_top() {
+		let ranges = [IdRange::parse("4294967295:7:1").unwrap(), IdRange::parse("10:4294967290:6").unwrap()];
+		assert_eq!(shift_out(&ranges, 4294967295), Some(7));
+		assert_eq!(shift_in(&ranges, 7), Some(4294967295));
+		assert_eq!(shift_out(&ranges, 15), Some(4294967295));
+		assert_eq!(shift_in(&ranges, 4294967295), Some(15));
+		assert_eq!(shift_in(&ranges, 6), None);
+		assert_eq!(shift_out(&ranges, 9), None);
+	}
+}